@@ -0,0 +1,109 @@
+#![feature(custom_test_frameworks)]
+#![test_runner(criterion::runner)]
+
+use std::{panic, process, thread};
+
+use aura::api::{aura_alloc, aura_free, aura_set_top_level_shards};
+use aura::constants::KB;
+use criterion::Criterion;
+use criterion_macro::criterion;
+use rand::prelude::*;
+
+fn criterion_bench_mamd_limit() -> Criterion { Criterion::default().sample_size(10) }
+
+/// The MA/MD stress pattern of `aura_mamd`: every thread allocates, frees its
+/// own objects, and sends some to other threads to free. Short-lived objects
+/// keep blocks going back and forth between the buckets and the top-level.
+fn mamd() {
+    // many allocator, many free site
+    let iterations_per_thread = 10000000usize;
+
+    let num_threads = num_cpus::get();
+    let mut handles = Vec::new();
+
+    let orig_hook = panic::take_hook();
+    {
+        panic::set_hook(Box::new(move |panic_info| {
+            // invoke the default handler and exit the process
+            orig_hook(panic_info);
+            process::exit(1);
+        }));
+    }
+
+    let mut receivers = Vec::new();
+    let mut senders = Vec::new();
+    for _ in 0..num_threads {
+        let (tx, rx) = crossbeam_channel::unbounded::<Box<u8>>();
+        receivers.push(rx);
+        senders.push(tx);
+    }
+
+    for _ in 0..num_threads {
+        let thread_rx = receivers.pop().unwrap();
+        let thread_tx_bank = senders.iter().map(|tx| tx.clone()).collect::<Vec<_>>();
+        handles.push(thread::spawn(move || {
+            let mut objects = Vec::<*mut u8>::new();
+            for _ in 0..iterations_per_thread {
+                match thread_rng().gen_range(0..4) {
+                    0 => {
+                        let obj = aura_alloc(thread_rng().gen_range(1..8 * KB));
+                        if !obj.is_null() {
+                            objects.push(obj);
+                        }
+                    },
+                    1 => {
+                        if objects.len() > 0 {
+                            let index = thread_rng().gen_range(0..objects.len());
+                            aura_free(objects.remove(index));
+                        }
+                    },
+                    2 => {
+                        if objects.len() > 0 {
+                            let index = thread_rng().gen_range(0..objects.len());
+                            let obj = objects.remove(index);
+                            loop {
+                                let recv_idx = thread_rng().gen_range(0..num_threads);
+                                match thread_tx_bank[recv_idx].send(unsafe { Box::from_raw(obj) }) {
+                                    Ok(_) => break,
+                                    Err(_) => continue,
+                                }
+                            }
+                        }
+                    },
+                    3 => {
+                        thread_rx.try_iter().for_each(|obj| aura_free(Box::into_raw(obj)));
+                    },
+                    _ => unreachable!(),
+                }
+            }
+            for tx in thread_tx_bank.into_iter() {
+                drop(tx);
+            }
+            thread_rx
+                .iter()
+                .chain(objects.into_iter().map(|p| unsafe { Box::from_raw(p) }))
+                .for_each(|obj| aura_free(Box::into_raw(obj)));
+        }));
+    }
+    for i in senders.into_iter() {
+        drop(i);
+    }
+
+    for handle in handles {
+        handle.join().unwrap();
+    }
+}
+
+#[criterion(criterion_bench_mamd_limit())]
+fn bench_top_level_mamd(criterion: &mut Criterion) {
+    let mut group = criterion.benchmark_group("Top-level MA/MD");
+    group.bench_function("one locked list", |b| {
+        aura_set_top_level_shards(1);
+        b.iter(mamd)
+    });
+    group.bench_function("per-CPU shards", |b| {
+        aura_set_top_level_shards(16);
+        b.iter(mamd)
+    });
+    group.finish();
+}
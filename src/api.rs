@@ -1,18 +1,413 @@
-use std::{mem, ptr};
+use std::alloc::{GlobalAlloc, Layout};
+use std::time::Duration;
+use std::{mem, process, ptr};
 
 use super::block::BlockHeader;
-use super::segment::{self, SegmentHeader};
-use super::{heap, top_level};
+use super::bucket::{bucket_select, bucket_to_size, BUCKETS};
+#[cfg(feature = "canary")]
+use super::canary;
+use super::mesh::MeshStats;
+#[cfg(feature = "debug-uaf")]
+use super::quarantine;
+use super::scavenger::{self, ScavengerConfig, ScavengerStats};
+use super::segment::{self, SegmentHeader, SegmentType};
+#[cfg(feature = "trace")]
+use super::trace::{self, TraceEvent};
+use super::{heap, segment_map, top_level, vm};
 use crate::constants::MB;
+use crate::Error;
+
+/// Every size class's stride is a multiple of this, so any slot satisfies it.
+pub const MIN_ALIGN: usize = 8;
+/// Largest alignment `aura_alloc_aligned` can honour.
+pub const MAX_ALIGN: usize = 2 * MB;
 
 pub fn aura_alloc(size: usize) -> *mut u8 { heap::thread_heap().alloc(size) }
-pub fn aura_free(object: *mut u8) { unsafe { find_block_for_object(object) }.free(object) }
+pub fn aura_free(object: *mut u8) {
+    if object.is_null() {
+        return
+    }
+    if cfg!(feature = "checked-free") {
+        check_segment(object).unwrap_or_else(|e| bad_free(e));
+    }
+    let seg_header = unsafe { segment_for_object(object) };
+    match seg_header.kind() {
+        SegmentType::Huge => unsafe { seg_header.free_dedicated() },
+        _ => free_in_block(object),
+    }
+}
+
+/// Free an object whose requested size is known, e.g. from a `Layout`, and
+/// that wasn't allocated with an alignment of its own. The size isn't needed to
+/// find the object's block, which is only looked up for it in debug builds:
+/// there the size is checked against the block's size class to catch frees
+/// with the wrong layout. A null `object` is fine.
+pub fn aura_free_sized(object: *mut u8, size: usize) { free_sized(object, size, MIN_ALIGN) }
+
+/// `aura_free_sized` for an object allocated with `align`.
+fn free_sized(object: *mut u8, size: usize, align: usize) {
+    if object.is_null() {
+        return
+    }
+    if cfg!(feature = "checked-free") {
+        check_segment(object).unwrap_or_else(|e| bad_free(e));
+    }
+    let seg_header = unsafe { segment_for_object(object) };
+    match seg_header.kind() {
+        SegmentType::Huge => {
+            debug_assert!(
+                size <= aura_usable_size(object),
+                "aura_free_sized: {:#?} was freed with size {} but only holds {}",
+                object,
+                size,
+                aura_usable_size(object)
+            );
+            unsafe { seg_header.free_dedicated() }
+        },
+        _ => {
+            debug_assert!(
+                in_bucket_for(size, align, unsafe { find_block_for_object(object) }.bucket_index()),
+                "aura_free_sized: {:#?} was freed with size {} but is in size class {}",
+                object,
+                size,
+                unsafe { find_block_for_object(object) }._object_size()
+            );
+            free_in_block(object)
+        },
+    }
+}
+
+/// Whether `bucket` is the one an object of `size` aligned to `align` gets: the
+/// one that serves its size, or the one `aura_alloc_aligned` picks instead.
+fn in_bucket_for(size: usize, align: usize, bucket: usize) -> bool {
+    let size = heap::reserved_size(size);
+    bucket == bucket_select(size)
+        || (align <= vm::page_size() && aligned_bucket(size, align) == Some(bucket))
+}
+
+/// Resize `object`, allocated with `align`, to `new_size` without moving it, if
+/// that can be done: a dedicated segment is resized through the VM layer,
+/// while an object from a block stays only if `new_size` is still for its size
+/// class, so that shrinking a large object moves it to a smaller one.
+pub(crate) unsafe fn resize_in_place(
+    object: *mut u8,
+    new_size: usize,
+    align: usize,
+) -> Option<*mut u8> {
+    match segment_for_object(object).kind() {
+        // the alignment of a dedicated segment's object is in where it
+        // starts, which resizing keeps
+        SegmentType::Huge => SegmentHeader::resize_dedicated(object, new_size),
+        _ => {
+            let bucket = find_block_for_object(object).bucket_index();
+            (new_size <= aura_usable_size(object) && in_bucket_for(new_size, align, bucket))
+                .then(|| object)
+        },
+    }
+}
+
+/// Free an object of a registered segment's block; with `debug-uaf`, by way of
+/// the calling thread's quarantine.
+fn free_in_block(object: *mut u8) {
+    #[cfg(feature = "canary")]
+    {
+        // a pointer that isn't an object of the block has no canary to check
+        let block = unsafe { find_block_for_object(object) };
+        if block.slot_index(object).is_some() {
+            canary::check(object, block);
+        }
+    }
+    #[cfg(feature = "debug-uaf")]
+    {
+        // the heap's destructor empties the quarantine as the thread exits,
+        // also on threads that only ever free
+        heap::thread_heap();
+        let size = unsafe { find_block_for_object(object) }._object_size();
+        quarantine::hold(object, size, release_to_block)
+    }
+    #[cfg(not(feature = "debug-uaf"))]
+    release_to_block(object)
+}
+
+/// Hand an object back to its block, onto its free list or public free list.
+pub(crate) fn release_to_block(object: *mut u8) {
+    let block = unsafe { find_block_for_object(object) };
+    if cfg!(feature = "checked-free") {
+        block.try_free(object).unwrap_or_else(|e| bad_free(e))
+    } else {
+        block.free(object)
+    }
+}
+
+/// Free an object, having checked that aura handed it out and that it hasn't
+/// been freed since; if not, nothing is freed. Doesn't take any lock to find
+/// the object's segment. A null `object` is fine, as with `free`. Never goes
+/// through the `debug-uaf` quarantine, so that the error is the caller's.
+pub fn aura_try_free(object: *mut u8) -> Result<(), Error> {
+    if object.is_null() {
+        return Ok(())
+    }
+    check_segment(object)?;
+    let seg_header = unsafe { segment_for_object(object) };
+    match seg_header.kind() {
+        SegmentType::Huge => unsafe { seg_header.free_dedicated() },
+        _ => unsafe { find_block_for_object(object) }.try_free(object)?,
+    }
+    Ok(())
+}
+
+/// Check that `object` points into one of a live segment's blocks, or is the
+/// object of a dedicated segment, so that its block header can be trusted.
+fn check_segment(object: *mut u8) -> Result<(), Error> {
+    let base = object as usize & !(4 * MB - 1);
+    if !segment_map::contains(base) {
+        return Err(Error::InvalidFree(object as usize))
+    }
+    let seg_header = unsafe { segment_for_object(object) };
+    let seg_offset = object as usize - base;
+    // the segment's own headers take up the first block's worth
+    let in_body = match seg_header.kind() {
+        SegmentType::Huge => seg_offset == seg_header.block_size(),
+        _ => seg_offset >= seg_header.block_size(),
+    };
+    if in_body {
+        Ok(())
+    } else {
+        Err(Error::InvalidFree(object as usize))
+    }
+}
+
+/// With `checked-free`, carrying on after a bad free would corrupt the heap.
+#[cold]
+fn bad_free(e: Error) -> ! {
+    eprintln!("aura: {}", e);
+    process::abort()
+}
+
+/// Number of bytes usable behind `object`: the stride of its size class, or
+/// the whole body of a dedicated segment. With `canary`, the end of an object
+/// from a block is taken up by the canary and the recorded size (`RESERVE`).
+/// Null has a usable size of 0.
+pub fn aura_usable_size(object: *mut u8) -> usize {
+    if object.is_null() {
+        return 0
+    }
+    let block = unsafe { find_block_for_object(object) };
+    #[cfg(feature = "canary")]
+    if !matches!(unsafe { segment_for_object(object) }.kind(), SegmentType::Huge) {
+        return canary::usable_size(block)
+    }
+    block._object_size()
+}
+
+/// Allocate `size` bytes aligned to `align` (a power of two, at most
+/// `MAX_ALIGN`). Up to the page size this picks a size class whose stride is a
+/// multiple of `align`; past that, or when no size class fits, the object gets
+/// a dedicated segment.
+pub fn aura_alloc_aligned(size: usize, align: usize) -> *mut u8 {
+    debug_assert!(align.is_power_of_two());
+    if align <= vm::page_size() {
+        if let Some(bucket) = aligned_bucket(heap::reserved_size(size), align) {
+            let object = heap::thread_heap().alloc_bucket(bucket);
+            #[cfg(feature = "canary")]
+            if !object.is_null() {
+                canary::arm(object, size, unsafe { find_block_for_object(object) });
+            }
+            return object
+        }
+    }
+    if align > MAX_ALIGN {
+        return ptr::null_mut()
+    }
+    SegmentHeader::new_dedicated(size, align).unwrap_or(ptr::null_mut())
+}
+
+/// Allocate `count * size` zeroed bytes, or return null if that overflows.
+pub fn aura_calloc(count: usize, size: usize) -> *mut u8 {
+    match count.checked_mul(size) {
+        Some(total) => alloc_zeroed(total, MIN_ALIGN),
+        None => ptr::null_mut(),
+    }
+}
 
-unsafe fn find_block_for_object(object: *mut u8) -> &'static mut BlockHeader {
-    let seg_header = mem::transmute::<_, &SegmentHeader>(object as usize & !(4 * MB - 1));
+/// Zeroed allocation. Slots that were never handed out since their block's
+/// memory came from the OS only need their free list link cleared, as far as
+/// it's within the object.
+pub(crate) fn alloc_zeroed(size: usize, align: usize) -> *mut u8 {
+    let obj = aura_alloc_aligned(size, align);
+    if obj.is_null() {
+        return obj
+    }
+    match unsafe { segment_for_object(obj) }.kind() {
+        // dedicated segments are always freshly mapped
+        SegmentType::Huge => (),
+        _ => unsafe {
+            if find_block_for_object(obj).last_alloc_fresh() {
+                ptr::write_bytes(obj, 0, size.min(mem::size_of::<*mut u8>()));
+            } else {
+                ptr::write_bytes(obj, 0, size);
+            }
+        },
+    }
+    obj
+}
+
+/// Resize an object. The object stays where it is if `new_size` still fits in
+/// its slot; otherwise it's moved to a new slot from the right size class.
+/// An object with a dedicated segment is grown or shrunk in place through the
+/// VM layer instead, shrinking giving the pages past its end back; it only
+/// moves if the address space after it is taken, and then without a copy
+/// where the VM layer can move pages.
+/// Like C `realloc`, a null `object` allocates and a zero `new_size` frees.
+pub fn aura_realloc(object: *mut u8, new_size: usize) -> *mut u8 {
+    if object.is_null() {
+        return aura_alloc(new_size)
+    }
+    if new_size == 0 {
+        aura_free(object);
+        return ptr::null_mut()
+    }
+    if cfg!(feature = "checked-free") {
+        check_segment(object).unwrap_or_else(|e| bad_free(e));
+    }
+    if let SegmentType::Huge = unsafe { segment_for_object(object) }.kind() {
+        if let Some(resized) = unsafe { SegmentHeader::resize_dedicated(object, new_size) } {
+            return resized
+        }
+    }
+    let old_size = aura_usable_size(object);
+    if new_size <= old_size {
+        return object
+    }
+    let new_object = aura_alloc(new_size);
+    if !new_object.is_null() {
+        unsafe { ptr::copy_nonoverlapping(object, new_object, old_size.min(new_size)) };
+        aura_free(object);
+    }
+    new_object
+}
+
+/// Mesh the calling thread's sparse blocks: pairs of blocks from the same size
+/// class whose live objects don't overlap are merged onto one block's physical
+/// pages, and the other's pages go back to the OS. Pointers into either block
+/// stay valid. `MeshStats::meshed` is the number of blocks released.
+///
+/// Other threads may keep writing to this thread's objects while it runs: a
+/// write to a block that's being moved waits until the move is done.
+pub fn aura_mesh() -> MeshStats { heap::thread_heap().mesh() }
+
+/// Set how long a block has to stay empty before its physical pages are given
+/// back to the OS, 10 ms by default. Purging happens as blocks are emptied, or
+/// in the scavenger.
+pub fn aura_set_purge_delay(delay: Duration) { top_level::set_purge_delay(delay) }
+
+/// Give every empty block's physical pages back to the OS now. Returns the
+/// number of blocks purged.
+pub fn aura_purge() -> usize { top_level::get().purge(0) }
+
+/// Set how many segments with no blocks in use are kept around, per segment
+/// type, instead of being unmapped; 1 by default. Segments kept beyond a
+/// lowered limit stay until they're used again.
+pub fn aura_set_segment_cache(segments: usize) { top_level::set_segment_cache(segments) }
+
+/// Set how many shards, at most 16, blocks handed back to the top-level are
+/// spread over by CPU; all 16 by default. With 1, all threads share one lock
+/// per list, e.g. to compare against.
+pub fn aura_set_top_level_shards(shards: usize) { top_level::set_shards(shards) }
+
+/// Put a guard page at the end of every small block (for objects of up to
+/// 8 KB) of the segments made from now on, so that overflowing a block faults;
+/// off by default. Large blocks can't spare a page: their largest size classes
+/// take up all of them. The space between a segment's block headers and its
+/// first block is always guarded.
+#[cfg(feature = "hardened")]
+pub fn aura_set_block_guards(enabled: bool) { segment::set_block_guards(enabled) }
+
+/// Set how many bytes of freed objects each thread holds back, poisoned, before
+/// their slots can be handed out again; 1 MB by default. With 0, freed objects
+/// go straight back to their blocks, still poisoned.
+#[cfg(feature = "debug-uaf")]
+pub fn aura_set_quarantine_bytes(bytes: usize) { quarantine::set_quarantine_bytes(bytes) }
+
+/// Write the calling thread's most recent allocs and frees to stderr, oldest
+/// first. Doesn't allocate.
+#[cfg(feature = "trace")]
+pub fn aura_trace_dump() { trace::dump() }
+
+/// Run `f` on each of the calling thread's most recent allocs and frees,
+/// oldest first.
+#[cfg(feature = "trace")]
+pub fn aura_trace_events(f: impl FnMut(&TraceEvent)) { trace::for_each_event(f) }
+
+/// Start a background thread that meshes every thread's sparse blocks and
+/// gives empty blocks' pages back to the OS, within the rate and CPU budget of
+/// `config`. Returns false if it's already running.
+pub fn aura_scavenger_start(config: ScavengerConfig) -> bool { scavenger::start(config) }
+/// Stop the background thread, waiting for the pass it's in to end.
+pub fn aura_scavenger_stop() { scavenger::stop() }
+pub fn aura_scavenger_pause() { scavenger::set_paused(true) }
+pub fn aura_scavenger_resume() { scavenger::set_paused(false) }
+pub fn aura_scavenger_configure(config: ScavengerConfig) { scavenger::configure(config) }
+pub fn aura_scavenger_stats() -> ScavengerStats { scavenger::stats() }
+
+unsafe fn segment_for_object(object: *mut u8) -> &'static SegmentHeader {
+    mem::transmute::<_, &SegmentHeader>(object as usize & !(4 * MB - 1))
+}
+
+pub(crate) unsafe fn find_block_for_object(object: *mut u8) -> &'static mut BlockHeader {
+    let seg_header = segment_for_object(object);
     let seg_offset = object as usize & (4 * MB - 1);
     let block_idx = (seg_offset / seg_header.block_size()) - 1;
     mem::transmute::<*mut BlockHeader, &'static mut BlockHeader>(
         seg_header.block_header(block_idx).get(),
     )
 }
+
+/// Bucket whose stride is a multiple of `align`. Block bodies are aligned to
+/// the block size, so every slot in such a bucket is aligned.
+fn aligned_bucket(size: usize, align: usize) -> Option<usize> {
+    let mut bucket = bucket_select(size.max(align));
+    while bucket < BUCKETS && 0 != bucket_to_size(bucket + 1) % align {
+        bucket += 1;
+    }
+    if bucket < BUCKETS {
+        Some(bucket)
+    } else {
+        None
+    }
+}
+
+/// Aura as a `GlobalAlloc`:
+///
+/// ```ignore
+/// #[global_allocator]
+/// static GLOBAL: aura::Aura = aura::Aura;
+/// ```
+pub struct Aura;
+
+unsafe impl GlobalAlloc for Aura {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        aura_alloc_aligned(layout.size(), layout.align())
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        free_sized(ptr, layout.size(), layout.align())
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        alloc_zeroed(layout.size(), layout.align())
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        // it'll be freed with `new_size`, which has to be for its size class
+        if let Some(resized) = resize_in_place(ptr, new_size, layout.align()) {
+            return resized
+        }
+        let new_ptr = aura_alloc_aligned(new_size, layout.align());
+        if !new_ptr.is_null() {
+            ptr::copy_nonoverlapping(ptr, new_ptr, layout.size().min(new_size));
+            free_sized(ptr, layout.size(), layout.align());
+        }
+        new_ptr
+    }
+}
@@ -0,0 +1,112 @@
+//! Write barrier for meshing.
+//!
+//! While a block is copied into its mesh partner its pages are read-only, so a
+//! thread writing to one of its objects faults instead of writing to pages
+//! that are about to be thrown away. The fault handler holds the writer until
+//! the remap is done, then returns; the write is retried against the new
+//! mapping.
+
+use std::cell::Cell;
+use std::mem::{self, MaybeUninit};
+use std::ptr;
+
+use libc::{c_int, c_void, siginfo_t};
+use parking_lot::Once;
+
+use super::api::find_block_for_object;
+use super::segment::{self, SegmentType};
+use crate::constants::MB;
+
+#[cfg(target_os = "linux")]
+const BARRIER_SIGNALS: [c_int; 1] = [libc::SIGSEGV];
+// Darwin reports some protection faults as SIGBUS
+#[cfg(not(target_os = "linux"))]
+const BARRIER_SIGNALS: [c_int; 2] = [libc::SIGSEGV, libc::SIGBUS];
+
+static INSTALL: Once = Once::new();
+static mut PREV_ACTIONS: [MaybeUninit<libc::sigaction>; BARRIER_SIGNALS.len()] =
+    [MaybeUninit::uninit(); BARRIER_SIGNALS.len()];
+
+thread_local! {
+    // the last fault this thread let through without a barrier up; const, so
+    // that the fault handler's first use doesn't run a lazy initialiser, which
+    // isn't async-signal-safe
+    static LAST_FAULT: Cell<usize> = const { Cell::new(0) };
+}
+
+/// Install the fault handler, once. Must happen before any block is made
+/// read-only.
+pub fn install() {
+    INSTALL.call_once(|| unsafe {
+        let mut action: libc::sigaction = mem::zeroed();
+        action.sa_sigaction = handle_fault as usize;
+        action.sa_flags = libc::SA_SIGINFO | libc::SA_ONSTACK | libc::SA_RESTART;
+        libc::sigemptyset(&mut action.sa_mask);
+        for (i, &sig) in BARRIER_SIGNALS.iter().enumerate() {
+            if 0 != libc::sigaction(sig, &action, PREV_ACTIONS[i].as_mut_ptr()) {
+                panic!("failed to install the mesh write barrier for signal {}", sig);
+            }
+        }
+    });
+}
+
+#[cfg(target_os = "linux")]
+unsafe fn fault_addr(info: *mut siginfo_t) -> usize { (*info).si_addr() as usize }
+#[cfg(not(target_os = "linux"))]
+unsafe fn fault_addr(info: *mut siginfo_t) -> usize { (*info).si_addr as usize }
+
+extern "C" fn handle_fault(sig: c_int, info: *mut siginfo_t, context: *mut c_void) {
+    let addr = unsafe { fault_addr(info) };
+    if unsafe { wait_out_barrier(addr) } {
+        return
+    }
+    unsafe { forward(sig, info, context) }
+}
+
+/// Whether the fault at `addr` was (or may have been) a write hitting a
+/// barrier; if so, returns once the barrier is down.
+unsafe fn wait_out_barrier(addr: usize) -> bool {
+    if !segment::may_contain(addr as *const u8) {
+        return false
+    }
+    // a released segment's header faults again here, which ends the process
+    // just as the original fault would have
+    let seg_header = &*((addr & !(4 * MB - 1)) as *const segment::SegmentHeader);
+    if let SegmentType::Huge = seg_header.kind() {
+        return false
+    }
+    if (addr & (4 * MB - 1)) < seg_header.block_size() {
+        // segment header
+        return false
+    }
+    let block = find_block_for_object(addr as *mut u8);
+    if block.wait_out_barrier() {
+        LAST_FAULT.with(|last| last.set(0));
+        return true
+    }
+    // the barrier may have come down between the fault and the check above:
+    // retry once before deciding the fault is someone else's
+    LAST_FAULT.with(|last| last.replace(addr) != addr)
+}
+
+/// Hand a fault that isn't ours to the handler that was installed before.
+unsafe fn forward(sig: c_int, info: *mut siginfo_t, context: *mut c_void) {
+    LAST_FAULT.with(|last| last.set(0));
+    let i = BARRIER_SIGNALS.iter().position(|&s| s == sig).unwrap_or(0);
+    let prev = &*PREV_ACTIONS[i].as_ptr();
+    match prev.sa_sigaction {
+        libc::SIG_DFL | libc::SIG_IGN => {
+            // put it back; the faulting instruction runs again and gets it
+            libc::sigaction(sig, prev, ptr::null_mut());
+        },
+        handler if 0 != prev.sa_flags & libc::SA_SIGINFO => {
+            let handler =
+                mem::transmute::<usize, extern "C" fn(c_int, *mut siginfo_t, *mut c_void)>(handler);
+            handler(sig, info, context)
+        },
+        handler => {
+            let handler = mem::transmute::<usize, extern "C" fn(c_int)>(handler);
+            handler(sig)
+        },
+    }
+}
@@ -1,22 +1,30 @@
 use std::borrow::BorrowMut;
 use std::cell::{RefCell, UnsafeCell};
+use std::num::NonZeroU64;
 use std::ops::Deref;
 use std::sync::atomic::*;
-use std::thread::{self, ThreadId};
+use std::thread;
 use std::{mem, ptr};
 
+use parking_lot::lock_api::RawMutex as _;
 use parking_lot::*;
 use rand::distributions::{Distribution, Uniform};
 use rand::prelude::*;
+use rand::rngs::OsRng;
 use rand::RngCore;
 use rand_xoshiro::Xoshiro256StarStar;
 
-use super::bucket::Bucket;
-use super::free_list::{AnyFreeList, AtomicPushFreeList, BiFreeList, FreeListPop, FreeListPush};
-use super::mesh::MeshMask;
+use super::barrier;
+use super::bucket::{self, Bucket};
+use super::free_list::{
+    AnyFreeList, AtomicPushFreeList, BiFreeList, FreeListPop, FreeListPush, LinkCodec,
+};
+use super::mesh::{self, MeshMask};
 use super::segment::SegmentHeader;
-use super::top_level;
+use super::vm::{VMRegion, VirtualRegion};
+use super::{heap, top_level};
 use crate::constants::{GB, KB, MB};
+use crate::Error;
 
 #[derive(Debug)]
 pub struct AtomicTaggedPtr(AtomicUsize);
@@ -26,7 +34,13 @@ pub const PTR_TAG_MASK: usize = 0x7usize;
 impl AtomicTaggedPtr {
     pub fn new<T>(p: *mut T) -> AtomicTaggedPtr { AtomicTaggedPtr(AtomicUsize::new(p as usize)) }
 
-    pub fn set_ptr<T>(&mut self, p: *mut T) { self.0.store(p as usize, Ordering::SeqCst); }
+    /// Keeps the tag.
+    pub fn set_ptr<T>(&mut self, p: *mut T) {
+        debug_assert!(0 == (p as usize & PTR_TAG_MASK));
+        let _ = self.0.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |curr| {
+            Some(p as usize | (curr & PTR_TAG_MASK))
+        });
+    }
 
     pub fn ptr<T>(&mut self) -> *mut T { (self.0.load(Ordering::SeqCst) & !PTR_TAG_MASK) as *mut T }
 
@@ -50,9 +64,14 @@ const BLOCK_FLAGS_NONE: u64 = 0u64;
 const BLOCK_FLAGS_IS_ACTIVE: u64 = 1u64;
 
 pub const BLOCK_FLAGS_MAYBE_FREE: u64 = 2u64;
-const BLOCK_FLAGS_MAYBE_MESH: u64 = 4u64;
+pub const BLOCK_FLAGS_MAYBE_MESH: u64 = 4u64;
 
 pub const BLOCK_FLAGS_FREE_LOCK: u64 = 8u64;
+/// The block's pages are read-only while it's being meshed.
+pub const BLOCK_FLAGS_BARRIER: u64 = 16u64;
+
+/// Most objects a block can hold: one per bit of the mesh mask.
+pub const MAX_BLOCK_OBJECTS: usize = 64 * 64;
 
 const MESH_TAG_NORMAL: u8 = 0;
 const MESH_TAG_MESHING: u8 = 1;
@@ -64,6 +83,8 @@ const MESH_TAG_MESHING: u8 = 1;
 /// Invariant(alloc_count == 0 => state in { empty }
 ///     |> alloc_count != 0 => state not in { empty })
 /// Invariant(flags->not is_active => load alloc_count >= .alloc_count.)
+/// Invariant(mesh not null => block holds no objects of its own: its pages
+///     are mesh's; it's in no bucket, or on its bucket's meshed-away list)
 pub struct BlockHeader {
     alloc_list: BiFreeList<u8>,
     free_list: BiFreeList<u8>,
@@ -72,25 +93,42 @@ pub struct BlockHeader {
     slow_interior: *mut u8,
     segment_idx: usize,
     pub next_in_bucket: *mut BlockHeader,
-    padding0: [u64; 1],
-
-    padding1: [u64; 3],
+    // body hasn't been written to since the OS handed it out zero-filled
+    body_zeroed: bool,
+    // alloc_list is still the chain built by format over a zeroed body
+    alloc_list_fresh: bool,
+    // empty, and its pages have been given back to the OS
+    purged: bool,
+    padding0: [u8; 5],
+
+    // blocks meshed into this one, linked through next_meshed
+    meshed: *mut BlockHeader,
+    next_meshed: *mut BlockHeader,
+    // Bucket::meshed_away_list
+    next_meshed_away: *mut BlockHeader,
     pub_free_list: AtomicPushFreeList<u8>,
     bucket: *mut Bucket,
-    tid: Option<ThreadId>,
+    tid: Option<NonZeroU64>,
     // Bucket::maybe_free_list
     free_mutex: RawMutex,
     padding1_0: [u8; 7],
     maybe_next_free: *mut BlockHeader,
 
-    padding2: [u64; 3],
+    // XORed into the links of free objects, with the `hardened` feature
+    link_key: usize,
+    // the bucket list is doubly linked, so that blocks unlink in place
+    pub prev_in_bucket: *mut BlockHeader,
+    // when the block last went to the top-level empty, for purging
+    emptied_at: u64,
     pub flags: AtomicU64,
     alloc_count: AtomicUsize,
     // Invariant(state in { meshing } => mesh'tag'meshing
     //      |> mesh'tag'normal => state not in { meshing })
     mesh: AtomicTaggedPtr,
     mesh_mutex: RawMutex,
-    padding2_0: [u8; 7],
+    padding2_0: [u8; 3],
+    // frees running without the mesh mutex
+    frees_running: AtomicU32,
     maybe_next_mesh: *mut BlockHeader,
 
     mesh_mask: MeshMask<64>,
@@ -107,6 +145,7 @@ impl std::fmt::Debug for BlockHeader {
             .field("slow_interior", &self.slow_interior)
             .field("segment_idx", &self.segment_idx)
             .field("next_in_bucket", &self.next_in_bucket)
+            .field("prev_in_bucket", &self.prev_in_bucket)
             .field("pub_free_list", &self.pub_free_list)
             .field(
                 "bucket",
@@ -117,6 +156,7 @@ impl std::fmt::Debug for BlockHeader {
             .field("flags", &self.flags)
             .field("alloc_count", &self.alloc_count)
             .field("mesh", &self.mesh)
+            .field("meshed", &self.meshed)
             .field("maybe_next_mesh", &self.maybe_next_mesh)
             // .field("mesh_mask", &self.mesh_mask)
             .finish()
@@ -144,29 +184,35 @@ impl BlockHeader {
             if self.alloc_list.is_empty() {
                 return ptr::null_mut()
             }
+            self.alloc_list_fresh = false;
         }
         let prev_cnt = self.alloc_count.fetch_add(1, Ordering::SeqCst);
-        eprintln!(
-            "{}T prev_cnt={}, now={} on {:#?}",
-            thread::current().id().as_u64(),
-            prev_cnt,
-            self.alloc_count.load(Ordering::SeqCst),
-            self as *const BlockHeader
-        );
+        trace!(Alloc, self as *const BlockHeader, prev_cnt);
         // eprintln!("alloc on {:#?}", self as *const BlockHeader);
         // update mesh mask
         // eprintln!("getting addr...");
-        let addr = self.alloc_list.pop();
+        let addr = self.alloc_list.pop(self.codec());
         // eprintln!("addr = {:#?}", addr);
         let raw_offset = unsafe { addr.offset_from(self.slow_interior) };
         //eprintln!("raw_offset = {}", raw_offset);
         let offset = raw_offset as usize / self.object_size;
         self.mesh_mask.set(offset);
+        #[cfg(feature = "debug-uaf")]
+        crate::quarantine::check(addr, self);
         // return allocated object
         addr
     }
 
     pub fn free(&mut self, obj: *mut u8) {
+        // unchecked, so it can't fail
+        let _ = self.free_impl::<false>(obj);
+    }
+
+    /// Free `obj` only if it's the start of one of this block's slots, and
+    /// that slot is in use; otherwise nothing is touched.
+    pub fn try_free(&mut self, obj: *mut u8) -> Result<(), Error> { self.free_impl::<true>(obj) }
+
+    fn free_impl<const CHECKED: bool>(&mut self, obj: *mut u8) -> Result<(), Error> {
         debug_assert!(
             obj >= self.base()
                 && obj
@@ -175,28 +221,61 @@ impl BlockHeader {
                         // self.base().offset(4 * KB as isize)
                     }
         );
+        // frees and meshing exclude each other: a free only takes the mesh
+        // mutex while a mesh keeps frees out (`fence_frees`)
+        self.frees_running.fetch_add(1, Ordering::SeqCst);
+        let locked = MESH_TAG_MESHING == self.mesh.tag();
+        if locked {
+            self.frees_running.fetch_sub(1, Ordering::SeqCst);
+            self.mesh_mutex.lock();
+        }
+        let meshed_into = self.mesh.ptr::<BlockHeader>();
+        if !meshed_into.is_null() {
+            self.end_free(locked);
+            // this block's pages are now those of the block it was meshed
+            // into, and so are its objects
+            let target = unsafe { &mut *meshed_into };
+            let offset = unsafe { obj.offset_from(self.base()) };
+            return target.free_impl::<CHECKED>(unsafe { target.base().offset(offset) })
+        }
         let is_pub = match self.tid {
             None => true,
-            Some(block_tid) => block_tid.as_u64() != thread::current().id().as_u64(),
+            Some(block_tid) => block_tid != heap::thread_id(),
         };
-        let prev_cnt2 = self.alloc_count.load(Ordering::SeqCst);
+        if CHECKED {
+            let result = match self.slot_index(obj) {
+                None => Err(Error::InvalidFree(obj as usize)),
+                Some(idx) if !self.mesh_mask.test_reset(idx) => {
+                    Err(Error::DoubleFree(obj as usize))
+                },
+                Some(_) => Ok(()),
+            };
+            if result.is_err() {
+                self.end_free(locked);
+                return result
+            }
+        } else {
+            let raw_offset = unsafe { obj.offset_from(self.slow_interior) };
+            self.mesh_mask.reset(raw_offset as usize / self.object_size);
+        }
+        // the quarantine poisoned it already, unless it was freed around it
+        #[cfg(feature = "debug-uaf")]
+        crate::quarantine::poison(obj, self.object_size);
         let prev_cnt = self.alloc_count.fetch_sub(1, Ordering::SeqCst);
-        eprintln!(
-            "{}T prev_cnt={} (<- {}), now={} as {:#?}",
-            thread::current().id().as_u64(),
-            prev_cnt,
-            prev_cnt2,
-            self.alloc_count.load(Ordering::SeqCst),
-            self as *const BlockHeader
-        );
+        trace!(Free, self as *const BlockHeader, prev_cnt);
 
         if is_pub {
             // eprintln!("pub free");
-            self.pub_free_list.push(obj);
+            self.pub_free_list.push(obj, self.codec());
         } else {
             // eprintln!("local free");
-            self.free_list.push(obj);
+            self.free_list.push(obj, self.codec());
         }
+        // the first free from another thread into an inactive block queues it
+        // for its owner, to allocate from again before taking a new block;
+        // before the free ends, so that a queued block isn't meshed
+        let delayed = is_pub && prev_cnt != 1 && self.try_mark_delayed();
+        self.end_free(locked);
         if prev_cnt == 1 {
             // eprintln!(
             //     "{}T prev_cnt: {} ({:#?})",
@@ -204,54 +283,358 @@ impl BlockHeader {
             //     prev_cnt,
             //     self as *const BlockHeader
             // );
-            let mut flags_cache = self.flags.load(Ordering::SeqCst);
-            if BLOCK_FLAGS_MAYBE_FREE != (flags_cache & BLOCK_FLAGS_MAYBE_FREE) {
-                // if !(self.alloc_count.load(Ordering::SeqCst) == 0
-                //     || BLOCK_FLAGS_IS_ACTIVE == flags_cache & BLOCK_FLAGS_IS_ACTIVE)
-                // {
-                //     eprintln!("{:#?}", self);
-                //     panic!("assertion violation (that one ,_,)");
-                // }
-                let mut wrote = true;
-                loop {
-                    if BLOCK_FLAGS_MAYBE_FREE == (flags_cache & BLOCK_FLAGS_MAYBE_FREE) {
-                        wrote = false;
-                        break
-                    }
-                    while BLOCK_FLAGS_FREE_LOCK == flags_cache & BLOCK_FLAGS_FREE_LOCK {
-                        flags_cache = self.flags.load(Ordering::SeqCst);
-                        thread::yield_now();
-                    }
-                    match self.flags.compare_exchange_weak(
-                        flags_cache,
-                        flags_cache | BLOCK_FLAGS_MAYBE_FREE,
-                        Ordering::SeqCst,
-                        Ordering::SeqCst,
-                    ) {
-                        Ok(_) => break,
-                        Err(actual) => flags_cache = actual,
-                    }
-                }
-                if wrote {
-                    if !self.bucket.is_null() {
-                        unsafe { &mut *self.bucket }.maybe_free(self as *mut BlockHeader);
-                    } else {
-                        let top_level = top_level::get();
-                        top_level.free(self);
-                    }
-                }
+            if self.mark_maybe_free() {
+                self.hand_to_owner();
+            }
+        } else {
+            if delayed {
+                self.hand_to_owner();
+            }
+            if mesh::is_sparse(self.count, prev_cnt - 1) {
+                self.note_sparse();
             }
         }
-        // TODO: Meshing
+        Ok(())
+    }
+
+    fn end_free(&self, locked: bool) {
+        if locked {
+            unsafe { self.mesh_mutex.unlock() };
+        } else {
+            self.frees_running.fetch_sub(1, Ordering::SeqCst);
+        }
+    }
+
+    /// Set MAYBE_FREE, once the free lock is free. Returns false if it was set
+    /// already: then the block is (about to be) queued for its owner anyway.
+    pub fn mark_maybe_free(&self) -> bool {
+        let mut flags_cache = self.flags.load(Ordering::SeqCst);
+        loop {
+            if BLOCK_FLAGS_MAYBE_FREE == (flags_cache & BLOCK_FLAGS_MAYBE_FREE) {
+                return false
+            }
+            while BLOCK_FLAGS_FREE_LOCK == flags_cache & BLOCK_FLAGS_FREE_LOCK {
+                flags_cache = self.flags.load(Ordering::SeqCst);
+                thread::yield_now();
+            }
+            match self.flags.compare_exchange_weak(
+                flags_cache,
+                flags_cache | BLOCK_FLAGS_MAYBE_FREE,
+                Ordering::SeqCst,
+                Ordering::SeqCst,
+            ) {
+                Ok(_) => return true,
+                Err(actual) => flags_cache = actual,
+            }
+        }
+    }
+
+    /// `mark_maybe_free` for an inactive block in some bucket, without
+    /// waiting: a block whose free lock is held is being seen to already.
+    fn try_mark_delayed(&self) -> bool {
+        if self.bucket.is_null() {
+            return false
+        }
+        let busy = BLOCK_FLAGS_IS_ACTIVE | BLOCK_FLAGS_MAYBE_FREE | BLOCK_FLAGS_FREE_LOCK;
+        let mut flags = self.flags.load(Ordering::SeqCst);
+        while 0 == flags & busy {
+            match self.flags.compare_exchange_weak(
+                flags,
+                flags | BLOCK_FLAGS_MAYBE_FREE,
+                Ordering::SeqCst,
+                Ordering::SeqCst,
+            ) {
+                Ok(_) => return true,
+                Err(actual) => flags = actual,
+            }
+        }
+        false
+    }
+
+    /// Put a block marked MAYBE_FREE on its owner's maybe-free list, or give it
+    /// back to the top-level if the owner's thread has exited.
+    fn hand_to_owner(&mut self) {
+        // the bucket's thread may exit in the meantime, leaving the block to
+        // the top-level
+        loop {
+            let bucket = self.bucket;
+            if bucket.is_null() {
+                let top_level = top_level::get();
+                top_level.free(self);
+                return
+            }
+            if unsafe { &*bucket }.maybe_free(self as *mut BlockHeader) {
+                return
+            }
+        }
+    }
+
+    /// Offer an inactive block that's gone sparse to its bucket for meshing.
+    fn note_sparse(&mut self) {
+        let bucket = self.bucket;
+        let flags = self.flags.load(Ordering::SeqCst);
+        if bucket.is_null() || 0 != flags & (BLOCK_FLAGS_IS_ACTIVE | BLOCK_FLAGS_MAYBE_MESH) {
+            return
+        }
+        unsafe { &*bucket }.maybe_mesh(self as *mut BlockHeader);
     }
 
     pub fn allocated(&self) -> usize { self.alloc_count.load(Ordering::SeqCst) }
 }
 
+// Meshing
+impl BlockHeader {
+    /// Whether this block is one of `bucket`'s inactive blocks, sparse enough
+    /// to be worth meshing.
+    pub fn is_mesh_candidate(&mut self, bucket: *mut Bucket) -> bool {
+        self.bucket == bucket
+            && 0 == self.flags.load(Ordering::SeqCst) & BLOCK_FLAGS_IS_ACTIVE
+            && self.mesh.ptr::<BlockHeader>().is_null()
+            && mesh::is_sparse(self.count, self.allocated())
+    }
+
+    /// Whether this block's live slots are disjoint from `other`'s, and the
+    /// two together would fit in one block.
+    pub fn meshes_with(&self, other: &BlockHeader) -> bool {
+        self.object_size == other.object_size
+            && mesh::should_mesh(self.count, &[self.allocated(), other.allocated()])
+            && mesh::meshes_with(&self.mesh_mask, &other.mesh_mask)
+    }
+
+    /// Mesh this block into `dst`, a block of the same size class whose live
+    /// slots are disjoint from this one's: the live objects are copied across
+    /// and this block's pages are remapped onto `dst`'s, releasing this
+    /// block's physical memory. Frees through either block's addresses end up
+    /// in `dst` from then on.
+    ///
+    /// Neither block may be allocated from while this runs. Other threads may
+    /// keep writing to this block's objects: its pages are read-only until the
+    /// remap is done, and writers wait in the fault handler until then.
+    ///
+    /// Returns false, leaving both blocks as they were, if the blocks don't
+    /// mesh or the pages couldn't be remapped.
+    pub fn mesh_into(&mut self, dst: &mut BlockHeader) -> bool {
+        let (first, second) = if (self as *mut BlockHeader) < (dst as *mut BlockHeader) {
+            (&self.mesh_mutex as *const RawMutex, &dst.mesh_mutex as *const RawMutex)
+        } else {
+            (&dst.mesh_mutex as *const RawMutex, &self.mesh_mutex as *const RawMutex)
+        };
+        unsafe {
+            (*first).lock();
+            (*second).lock();
+        }
+        self.fence_frees();
+        dst.fence_frees();
+        let meshed = self.mesh_into_locked(dst);
+        dst.unfence_frees();
+        self.unfence_frees();
+        unsafe {
+            (*second).unlock();
+            (*first).unlock();
+        }
+        meshed
+    }
+
+    /// Keep frees out of the block, with its mesh mutex held: frees from now
+    /// on take the mutex as well, and the ones already running without it are
+    /// waited out.
+    fn fence_frees(&mut self) {
+        self.mesh.set_tag(MESH_TAG_MESHING);
+        while 0 != self.frees_running.load(Ordering::SeqCst) {
+            thread::yield_now();
+        }
+    }
+
+    fn unfence_frees(&mut self) { self.mesh.set_tag(MESH_TAG_NORMAL) }
+
+    fn mesh_into_locked(&mut self, dst: &mut BlockHeader) -> bool {
+        // only one level of meshing: everything that shares pages points
+        // straight at the block that owns them
+        // inactive blocks aren't allocated from, and stay inactive for as long
+        // as they have live objects, which frees can't take away from under
+        // the mesh mutexes; blocks in the top-level may be adopted any time
+        // neither can blocks queued for their owner to allocate from
+        if !self.meshed.is_null()
+            || self.bucket.is_null()
+            || dst.bucket.is_null()
+            || 0 != (self.flags.load(Ordering::SeqCst) | dst.flags.load(Ordering::SeqCst))
+                & (BLOCK_FLAGS_IS_ACTIVE | BLOCK_FLAGS_MAYBE_FREE)
+            || !self.mesh.ptr::<BlockHeader>().is_null()
+            || !dst.mesh.ptr::<BlockHeader>().is_null()
+            || 0 == self.allocated()
+            || 0 == dst.allocated()
+            || !self.meshes_with(dst)
+        {
+            return false
+        }
+        let span = self.get_segment().block_span();
+        let mut src_region = unsafe { VMRegion::from_raw_parts(self.base(), span) };
+        barrier::install();
+        self.flags.fetch_or(BLOCK_FLAGS_BARRIER, Ordering::SeqCst);
+        if src_region.prot(true, false).is_err() {
+            self.flags.fetch_and(!BLOCK_FLAGS_BARRIER, Ordering::SeqCst);
+            src_region.consume();
+            return false
+        }
+
+        let osize = self.object_size;
+        for idx in (0..self.count).filter(|&idx| self.mesh_mask.test(idx)) {
+            unsafe {
+                ptr::copy_nonoverlapping(
+                    self.base().add(idx * osize),
+                    dst.base().add(idx * osize),
+                    osize,
+                )
+            };
+            dst.mesh_mask.set(idx);
+        }
+
+        let dst_region = unsafe { VMRegion::from_raw_parts(dst.base(), span) };
+        let remapped = dst_region.map_to(0, span, self.base());
+        dst_region.consume();
+        match remapped {
+            Ok(overlay) => {
+                // the new mapping is writable: the barrier is down
+                overlay.consume();
+                src_region.consume();
+                self.flags.fetch_and(!BLOCK_FLAGS_BARRIER, Ordering::SeqCst);
+            },
+            Err(_) => {
+                src_region.prot(true, true).expect("failed to lift the mesh write barrier");
+                src_region.consume();
+                self.flags.fetch_and(!BLOCK_FLAGS_BARRIER, Ordering::SeqCst);
+                // the copies are garbage in slots that dst still owns as free
+                for idx in (0..self.count).filter(|&idx| self.mesh_mask.test(idx)) {
+                    dst.mesh_mask.reset(idx);
+                }
+                dst.rebuild_free_list();
+                return false
+            },
+        }
+        dst.rebuild_free_list();
+
+        dst.alloc_count.fetch_add(self.alloc_count.swap(0, Ordering::SeqCst), Ordering::SeqCst);
+        self.mesh_mask.clear();
+        self.alloc_list.swap(ptr::null_mut());
+        self.free_list.swap(ptr::null_mut());
+        self.pub_free_list.swap(ptr::null_mut());
+        self.mesh.set_ptr(dst as *mut BlockHeader);
+        self.next_meshed = dst.meshed;
+        dst.meshed = self as *mut BlockHeader;
+        true
+    }
+
+    /// Called from the write barrier's fault handler: if this block's pages
+    /// are read-only because it's being meshed, wait for the remap to finish
+    /// and return true.
+    pub fn wait_out_barrier(&self) -> bool {
+        if 0 == self.flags.load(Ordering::SeqCst) & BLOCK_FLAGS_BARRIER {
+            return false
+        }
+        // not `lock`: parking isn't async-signal-safe
+        while !self.mesh_mutex.try_lock() {
+            thread::yield_now();
+        }
+        unsafe { self.mesh_mutex.unlock() };
+        true
+    }
+
+    /// Index of the slot `obj` is the start of, if it's one of this block's.
+    pub fn slot_index(&self, obj: *mut u8) -> Option<usize> {
+        let raw_offset = (obj as usize).wrapping_sub(self.slow_interior as usize);
+        // an empty block may never have been formatted
+        if 0 == self.object_size || 0 != raw_offset % self.object_size {
+            return None
+        }
+        Some(raw_offset / self.object_size).filter(|&idx| idx < self.count)
+    }
+
+    /// Rebuild the free lists from the mesh mask: every slot that isn't live
+    /// goes on the local free list.
+    fn rebuild_free_list(&mut self) {
+        self.alloc_list.swap(ptr::null_mut());
+        self.pub_free_list.swap(ptr::null_mut());
+        self.free_list.swap(ptr::null_mut());
+        for idx in (0..self.count).rev().filter(|&idx| !self.mesh_mask.test(idx)) {
+            let slot = unsafe { self.base().add(idx * self.object_size) };
+            // a failed mesh may have left copies in free slots
+            #[cfg(feature = "debug-uaf")]
+            crate::quarantine::poison(slot, self.object_size);
+            self.free_list.push(slot, self.codec());
+        }
+        self.alloc_list_fresh = false;
+    }
+
+    /// Give every block meshed into this one fresh pages of its own again, and
+    /// hand it to the top-level as an empty block. Only valid once this block
+    /// is empty: nothing can be left in the pages they shared.
+    pub fn release_meshed(&mut self) {
+        if self.meshed.is_null() {
+            return
+        }
+        // wait out any free still running against the shared pages
+        self.mesh_mutex.lock();
+        self.fence_frees();
+        let mut curr = mem::replace(&mut self.meshed, ptr::null_mut());
+        self.unfence_frees();
+        unsafe { self.mesh_mutex.unlock() };
+
+        let span = self.get_segment().block_span();
+        let top_level = top_level::get();
+        while !curr.is_null() {
+            let child = unsafe { &mut *curr };
+            curr = child.next_meshed;
+            child.mesh_mutex.lock();
+            let mut region = unsafe { VMRegion::from_raw_parts(child.base(), span) };
+            if let Err(e) = region.detach() {
+                panic!("couldn't unmesh block {:#?}: {}", child as *const BlockHeader, e);
+            }
+            region.consume();
+            child.next_meshed = ptr::null_mut();
+            child.mesh.set_ptr::<BlockHeader>(ptr::null_mut());
+            child.body_zeroed = true;
+            child.purged = true;
+            // a child the scavenger meshed may still be in its bucket's list;
+            // then its bucket hands it over once it's unlinked (prep_retired)
+            let orphaned = child.bucket.is_null();
+            unsafe { child.mesh_mutex.unlock() };
+            if orphaned {
+                top_level.receive(child.bucket_index(), unsafe {
+                    child.get_segment().block_header(child.segment_idx)
+                });
+            }
+        }
+    }
+
+    /// Note when an empty block went to the top-level.
+    pub fn note_emptied(&mut self, now: u64) { self.emptied_at = now }
+
+    /// Give an empty block's physical pages back to the OS if it's been empty
+    /// for at least `delay` ns. Returns true if it did just now.
+    pub fn purge_if_idle(&mut self, now: u64, delay: u64) -> bool {
+        debug_assert!(0 == self.allocated() && self.meshed.is_null());
+        if self.purged || now.saturating_sub(self.emptied_at) < delay {
+            return false
+        }
+        let span = self.get_segment().block_span();
+        let mut region = unsafe { VMRegion::from_raw_parts(self.base(), span) };
+        let purged = region.purge();
+        region.consume();
+        match purged {
+            Ok(zeroed) => {
+                self.purged = true;
+                self.body_zeroed = zeroed;
+                true
+            },
+            Err(_) => false,
+        }
+    }
+}
+
 impl BlockHeader {
     pub fn prep_active(&mut self, bucket_ptr: *mut Bucket) {
         // need to update: tid, bucket (for now)
-        self.tid = Some(thread::current().id());
+        self.tid = Some(heap::thread_id());
         self.bucket = bucket_ptr;
         self.flags.fetch_or(BLOCK_FLAGS_IS_ACTIVE, Ordering::SeqCst);
     }
@@ -263,6 +646,81 @@ impl BlockHeader {
         self.flags.fetch_and(!BLOCK_FLAGS_IS_ACTIVE, Ordering::SeqCst);
     }
 
+    /// `prep_free` for a block that was meshed away and has just been unlinked
+    /// from its bucket. Returns true if the block was unmeshed in the meantime
+    /// and is now the caller's to hand to the top-level.
+    pub fn prep_retired(&mut self) -> bool {
+        self.mesh_mutex.lock();
+        self.prep_free();
+        let orphaned = self.mesh.ptr::<BlockHeader>().is_null();
+        unsafe { self.mesh_mutex.unlock() };
+        orphaned
+    }
+
+    /// Take the free lock: frees that empty the block wait for it before
+    /// handing the block to whoever owns it.
+    pub fn lock_free(&self) {
+        let mut flags = self.flags.load(Ordering::SeqCst);
+        loop {
+            while 0 != flags & BLOCK_FLAGS_FREE_LOCK {
+                thread::yield_now();
+                flags = self.flags.load(Ordering::SeqCst);
+            }
+            match self.flags.compare_exchange_weak(
+                flags,
+                flags | BLOCK_FLAGS_FREE_LOCK,
+                Ordering::SeqCst,
+                Ordering::SeqCst,
+            ) {
+                Ok(_) => return,
+                Err(actual) => flags = actual,
+            }
+        }
+    }
+
+    /// Take the free lock of an abandoned block, unless a free is about to
+    /// hand it back to the top-level (or has it locked already).
+    pub fn try_lock_free(&self) -> bool {
+        let mut flags = self.flags.load(Ordering::SeqCst);
+        while 0 == flags & (BLOCK_FLAGS_FREE_LOCK | BLOCK_FLAGS_MAYBE_FREE) {
+            match self.flags.compare_exchange_weak(
+                flags,
+                flags | BLOCK_FLAGS_FREE_LOCK,
+                Ordering::SeqCst,
+                Ordering::SeqCst,
+            ) {
+                Ok(_) => return true,
+                Err(actual) => flags = actual,
+            }
+        }
+        false
+    }
+
+    /// `prep_active` for an abandoned block taken from the top-level with its
+    /// free lock held; releases the lock. Objects freed by other threads while
+    /// the block was abandoned are on its public free list, which `alloc`
+    /// takes up once the rest is used.
+    pub fn prep_adopted(&mut self, bucket_ptr: *mut Bucket) {
+        // not while a scavenger mesh is deciding whether the block is inactive
+        self.mesh_mutex.lock();
+        self.prep_active(bucket_ptr);
+        unsafe { self.mesh_mutex.unlock() };
+        self.flags.fetch_and(!BLOCK_FLAGS_FREE_LOCK, Ordering::SeqCst);
+    }
+
+    /// Make an inactive block with free slots its bucket's active block again,
+    /// unless it's been meshed away. The bucket sets it up with `prep_active`.
+    pub fn reactivate(&mut self) -> bool {
+        // not while a scavenger mesh is deciding whether the block is inactive
+        self.mesh_mutex.lock();
+        let reactivated = self.mesh.ptr::<BlockHeader>().is_null() && self.allocated() < self.count;
+        if reactivated {
+            self.flags.fetch_or(BLOCK_FLAGS_IS_ACTIVE, Ordering::SeqCst);
+        }
+        unsafe { self.mesh_mutex.unlock() };
+        reactivated
+    }
+
     pub fn prep_inactive(&mut self) {
         // self.tid = None;
         // no need to set bucket
@@ -278,14 +736,21 @@ impl BlockHeader {
         self.maybe_next_mesh = new_ptr;
     }
 
+    pub fn _set_next_meshed_away(&mut self, new_ptr: *mut BlockHeader) {
+        self.next_meshed_away = new_ptr;
+    }
+
     pub fn _maybe_next_free(&self) -> *mut BlockHeader { self.maybe_next_free }
     pub fn _maybe_next_mesh(&self) -> *mut BlockHeader { self.maybe_next_mesh }
+    pub fn _next_meshed_away(&self) -> *mut BlockHeader { self.next_meshed_away }
 }
 
 thread_local! (
-    static THREAD_RNG: RefCell<Xoshiro256StarStar> = RefCell::new(Xoshiro256StarStar::from_seed({
+    pub(crate) static THREAD_RNG: RefCell<Xoshiro256StarStar> = RefCell::new(Xoshiro256StarStar::from_seed({
+        // OsRng rather than thread_rng(): the latter allocates, and this may be
+        // initialised from inside the global allocator
         let mut data: <Xoshiro256StarStar as SeedableRng>::Seed = Default::default();
-        thread_rng().fill_bytes(&mut data[0..]);
+        OsRng.fill_bytes(&mut data[0..]);
         data
     }))
 );
@@ -300,21 +765,30 @@ impl BlockHeader {
             slow_interior: body,
             segment_idx: segment_idx,
             next_in_bucket: ptr::null_mut(),
+            // block headers are only created over new segments
+            body_zeroed: true,
+            alloc_list_fresh: false,
+            purged: true,
             padding0: Default::default(),
-            padding1: Default::default(),
+            meshed: ptr::null_mut(),
+            next_meshed: ptr::null_mut(),
+            next_meshed_away: ptr::null_mut(),
             pub_free_list: AtomicPushFreeList::new(),
             bucket: ptr::null_mut(),
             tid: None,
             free_mutex: <RawMutex as parking_lot::lock_api::RawMutex>::INIT,
             padding1_0: Default::default(),
             maybe_next_free: ptr::null_mut(),
-            padding2: Default::default(),
+            link_key: 0,
+            prev_in_bucket: ptr::null_mut(),
+            emptied_at: 0,
             flags: AtomicU64::new(0),
             alloc_count: AtomicUsize::new(0),
             mesh: AtomicTaggedPtr::new::<u8>(ptr::null_mut()),
             mesh_mutex: <RawMutex as parking_lot::lock_api::RawMutex>::INIT,
             maybe_next_mesh: ptr::null_mut(),
             padding2_0: Default::default(),
+            frees_running: AtomicU32::new(0),
             mesh_mask: MeshMask::new(),
         }
     }
@@ -323,23 +797,36 @@ impl BlockHeader {
     /// freelist setup.
     pub fn format(&mut self, osize: usize) -> *mut u8 {
         // THREAD_RNG.with(|rng| (*rng.borrow_mut()).next_u64());
-        let block_size = 1usize << self.get_segment().block_shift();
+        let span = self.get_segment().block_span();
         // eprintln!("Block size: {}", block_size);
         // let block_size = 4 * KB;
-        self.count = block_size / osize;
+        self.count = span / osize;
         // eprintln!("block_size={}, osize={}, count={}", block_size, osize,
         // self.count);
         self.object_size = osize;
 
-        let mut order: Vec<usize> = (0..self.count).collect();
-        THREAD_RNG.with(|rng| order.shuffle(&mut *rng.borrow_mut()));
+        // on the stack: format must not allocate
+        debug_assert!(self.count <= MAX_BLOCK_OBJECTS);
+        let mut order_buf = [0u16; MAX_BLOCK_OBJECTS];
+        let order = &mut order_buf[..self.count];
+        for (i, slot) in order.iter_mut().enumerate() {
+            *slot = i as u16;
+        }
+        THREAD_RNG.with(|rng| {
+            let mut rng = rng.borrow_mut();
+            order.shuffle(&mut *rng);
+            if cfg!(feature = "hardened") {
+                self.link_key = rng.next_u64() as usize;
+            }
+        });
+        let codec = self.codec();
         // eprintln!(
         //     "Shuffled fmt vec: {}",
         //     order.iter().map(|n| format!("{}", n)).collect::<Vec<_>>().join(", ")
         // );
         let interior: *mut u8 = self.slow_interior;
         let mut curr: *mut *mut u8 =
-            unsafe { interior.offset((order[0] * osize) as isize) } as *mut *mut u8;
+            unsafe { interior.offset((order[0] as usize * osize) as isize) } as *mut *mut u8;
         // let mut next: *mut *mut u8 = unsafe {
         // mem::MaybeUninit::uninit().assume_init() };
         let mut next: *mut *mut u8;
@@ -347,11 +834,21 @@ impl BlockHeader {
         self.alloc_list.swap(curr as *mut u8);
         self.free_list.swap(ptr::null_mut());
         self.pub_free_list.swap(ptr::null_mut());
+        self.mesh_mask.clear();
+        self.alloc_list_fresh = self.body_zeroed;
+        self.body_zeroed = false;
+        self.purged = false;
+        // every free slot is poisoned, fresh ones included
+        #[cfg(feature = "debug-uaf")]
+        {
+            crate::quarantine::poison(interior, self.count * osize);
+            self.alloc_list_fresh = false;
+        }
 
         for i in 0..self.count - 1 {
             use std::io::Write;
 
-            let tmp1 = unsafe { interior.offset((order[i + 1] * osize) as isize) };
+            let tmp1 = unsafe { interior.offset((order[i + 1] as usize * osize) as isize) };
             let tmp2 = tmp1 as *mut *mut u8;
             next = tmp2;
             // eprintln!(
@@ -362,26 +859,48 @@ impl BlockHeader {
             //     order[i + 1],
             //     next
             // );
-            unsafe { *curr = next as *mut u8 };
+            unsafe { *curr = codec.encode(next as *mut u8) };
             curr = next;
         }
-        unsafe { *curr = ptr::null_mut() };
+        unsafe { *curr = codec.encode(ptr::null_mut()) };
 
         ptr::null_mut()
     }
 
+    /// Format for a dedicated segment: a single object, live from the start.
+    pub fn format_dedicated(&mut self, osize: usize) {
+        self.count = 1;
+        self.object_size = osize;
+        self.alloc_count.store(1, Ordering::SeqCst);
+        self.mesh_mask.set(0);
+    }
+
     pub fn get_segment(&self) -> &SegmentHeader {
         let addr = unsafe { mem::transmute::<_, *mut u8>(self) as usize };
         unsafe { mem::transmute::<_, &SegmentHeader>((addr & !(4 * MB - 1)) as *mut u8) }
     }
 
     pub fn base(&self) -> *mut u8 { self.slow_interior }
+
+    fn codec(&self) -> LinkCodec {
+        let end = unsafe { self.base().add(self.get_segment().block_span()) };
+        LinkCodec::new(self.link_key, self.base(), end)
+    }
+
+    /// Whether the object most recently returned by `alloc` had never been
+    /// handed out since its memory came zero-filled from the OS. Such an object
+    /// is all zeroes save for its free list link word.
+    pub fn last_alloc_fresh(&self) -> bool { self.alloc_list_fresh }
 }
 
 impl BlockHeader {
     pub fn _count(&self) -> usize { self.count }
     pub fn _object_size(&self) -> usize { self.object_size }
+    /// Index of the bucket the block is formatted for: objects are sized for
+    /// the top of their bucket's range, which is the next bucket's start.
+    pub fn bucket_index(&self) -> usize { bucket::bucket_select(self.object_size - 1) }
     pub fn _segment_idx(&self) -> usize { self.segment_idx }
+    pub fn _bucket(&self) -> *mut Bucket { self.bucket }
 }
 
 #[cfg(test)]
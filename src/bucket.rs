@@ -3,10 +3,12 @@ use std::default::Default;
 use std::sync::atomic::{AtomicPtr, AtomicUsize, Ordering};
 use std::{intrinsics, mem, ptr};
 
+use parking_lot::lock_api::RawMutex as _;
 use parking_lot::RawMutex;
 
 use super::block::{self, BlockHeader};
 use super::free_list::{AtomicPushFreeList, FreeListPush};
+use super::mesh::{self, MeshStats, MAX_MESH_CANDIDATES, MESHING_SUPPORTED, SPLIT_MESHER_PROBES};
 use super::{bucket, top_level};
 use crate::constants::KB;
 use crate::util::extrinsic_bsr;
@@ -17,7 +19,12 @@ pub struct Bucket {
     active: AtomicPtr<BlockHeader>,
     maybe_free_list: AtomicPtr<BlockHeader>,
     maybe_mesh_list: AtomicPtr<BlockHeader>,
+    // blocks the scavenger meshed away, to be unlinked by the owning thread
+    meshed_away_list: AtomicPtr<BlockHeader>,
     count: AtomicUsize,
+    // held by other threads to push onto the lists above, and while the bucket
+    // is abandoned
+    lists_mutex: RawMutex,
 }
 
 impl std::fmt::Debug for Bucket {
@@ -58,6 +65,7 @@ impl std::fmt::Debug for Bucket {
             .field("active", &self.active)
             .field("maybe_free_list", &self.maybe_free_list)
             .field("maybe_mesh_list", &self.maybe_mesh_list)
+            .field("meshed_away_list", &self.meshed_away_list)
             .field("count", &self.count)
             .finish()
     }
@@ -70,10 +78,8 @@ impl Bucket {
         if maybe_active.is_null() {
             // println!("Null case");
             let bhp = self.source_block(bucket_idx);
-            let bh = unsafe { &mut *bhp };
-            bh.next_in_bucket = ptr::null_mut();
-            self.active.swap(bhp, Ordering::SeqCst);
-            return bh.alloc()
+            self.push(bhp);
+            return unsafe { &mut *bhp }.alloc()
         }
         // println!("General case");
         let maybe_active = unsafe { &mut *maybe_active };
@@ -85,78 +91,89 @@ impl Bucket {
         }
         // println!("Pull case");
         let bhp = self.source_block(bucket_idx);
-        let bh = unsafe { &mut *bhp };
-        bh.next_in_bucket = self.active.load(Ordering::SeqCst);
-        unsafe { &mut *bh.next_in_bucket }.prep_inactive();
-        self.active.swap(bhp, Ordering::SeqCst);
-        bh.alloc()
+        maybe_active.prep_inactive();
+        self.push(bhp);
+        unsafe { &mut *bhp }.alloc()
+    }
+
+    /// Make a block the active one, at the head of the bucket list.
+    fn push(&mut self, block_header: *mut BlockHeader) {
+        let b_ref = unsafe { &mut *block_header };
+        let head = self.active.load(Ordering::SeqCst);
+        b_ref.next_in_bucket = head;
+        b_ref.prev_in_bucket = ptr::null_mut();
+        if !head.is_null() {
+            unsafe { &mut *head }.prev_in_bucket = block_header;
+        }
+        self.active.store(block_header, Ordering::SeqCst);
     }
 
     fn source_block(&mut self, bucket_idx: usize) -> *mut BlockHeader {
-        // 1. clean up free list
+        // 0. drop blocks that were meshed away
+
+        let mut meshed_away = self.meshed_away_list.swap(ptr::null_mut(), Ordering::SeqCst);
+        while !meshed_away.is_null() {
+            let next = unsafe { &*meshed_away }._next_meshed_away();
+            self.retire(meshed_away);
+            meshed_away = next;
+        }
+
+        // 1. clean up free list: blocks that were emptied, and blocks that
+        // other threads freed objects into after they went inactive
 
         let mut first = None;
         let mut free_list = self.maybe_free_list.swap(ptr::null_mut(), Ordering::SeqCst);
         let top_level = top_level::get();
         while !free_list.is_null() {
             let free_list_ref = unsafe { &mut *free_list };
+            let next_free = free_list_ref._maybe_next_free();
             // experience has shown that, in fact, it will occur that there are
             // duds in the free list
-            let is_free =
-                free_list != self.active.load(Ordering::SeqCst) && free_list_ref.allocated() == 0;
+            let is_active = free_list == self.active.load(Ordering::SeqCst);
 
-            if is_free {
+            if !is_active && free_list_ref.allocated() == 0 {
                 free_list_ref.flags.fetch_xor(
                     block::BLOCK_FLAGS_FREE_LOCK | block::BLOCK_FLAGS_MAYBE_FREE,
                     Ordering::SeqCst,
                 );
 
-                let next_free = free_list_ref._maybe_next_free();
-                let mut curr = self.active.load(Ordering::SeqCst);
-                let mut removed = false;
-                while !curr.is_null() {
-                    let b_ref = unsafe { &mut *curr };
-                    if b_ref.next_in_bucket == free_list {
-                        b_ref.next_in_bucket = free_list_ref.next_in_bucket;
-                        removed = true;
-                        break
-                    }
-                    curr = b_ref.next_in_bucket;
-                }
-                if !removed {
-                    use std::io::Write;
-                    panic!("couldn't find block {:#?} in self {:#?}", free_list_ref, self);
-                }
+                self.unlink(free_list);
 
                 match first {
                     None => {
                         free_list_ref
                             .flags
                             .fetch_and(!block::BLOCK_FLAGS_FREE_LOCK, Ordering::SeqCst);
+                        free_list_ref.release_meshed();
                         first = Some(free_list_ref)
                     },
                     Some(_) => {
                         free_list_ref.prep_free();
-                        top_level.receive(
-                            bucket::bucket_select(free_list_ref._object_size()),
-                            unsafe {
-                                free_list_ref
-                                    .get_segment()
-                                    .block_header(free_list_ref._segment_idx())
-                            },
-                        );
+                        top_level.receive(free_list_ref.bucket_index(), unsafe {
+                            free_list_ref.get_segment().block_header(free_list_ref._segment_idx())
+                        });
                     },
                 };
-
-                free_list = next_free;
+            } else if !is_active && first.is_none() && free_list_ref.reactivate() {
+                self.unlink(free_list);
+                free_list_ref.flags.fetch_and(!block::BLOCK_FLAGS_MAYBE_FREE, Ordering::SeqCst);
+                first = Some(free_list_ref)
             } else {
                 free_list_ref.flags.fetch_and(!block::BLOCK_FLAGS_MAYBE_FREE, Ordering::SeqCst);
-                free_list = free_list_ref._maybe_next_free();
+                // a free that emptied it meanwhile saw it queued, and left it
+                if !is_active && free_list_ref.allocated() == 0 && free_list_ref.mark_maybe_free() {
+                    self.maybe_free(free_list);
+                }
             }
+            free_list = next_free;
         }
         let bh = match first {
             Some(bh) => bh,
             None => {
+                // blocks left behind by exited threads come first
+                if let Some(bh) = top_level.adopt(bucket_idx, self as *mut Bucket) {
+                    return bh
+                }
                 let resp = top_level.request(bucket_idx);
                 if let None = resp {
                     return ptr::null_mut()
@@ -168,28 +185,227 @@ impl Bucket {
 
         bh as *mut BlockHeader
     }
+
+    /// Remove an inactive block from the bucket list.
+    fn unlink(&mut self, block_header: *mut BlockHeader) {
+        let b_ref = unsafe { &mut *block_header };
+        let (prev, next) = (b_ref.prev_in_bucket, b_ref.next_in_bucket);
+        if prev.is_null() {
+            // only the active block has nothing before it
+            if self.active.load(Ordering::SeqCst) != block_header {
+                debug_assert!(false, "block {:#?} isn't in bucket {:#?}", b_ref, self);
+                return
+            }
+            self.active.store(next, Ordering::SeqCst);
+        } else {
+            unsafe { &mut *prev }.next_in_bucket = next;
+        }
+        if !next.is_null() {
+            unsafe { &mut *next }.prev_in_bucket = prev;
+        }
+        b_ref.next_in_bucket = ptr::null_mut();
+        b_ref.prev_in_bucket = ptr::null_mut();
+    }
+
+    /// Remove a block that was meshed away from the bucket list. If the block
+    /// it was meshed into has been emptied since, it falls to us to hand it
+    /// back to the top-level.
+    fn retire(&mut self, block_header: *mut BlockHeader) {
+        self.unlink(block_header);
+        let b_ref = unsafe { &mut *block_header };
+        if b_ref.prep_retired() {
+            top_level::get().receive(b_ref.bucket_index(), unsafe {
+                b_ref.get_segment().block_header(b_ref._segment_idx())
+            });
+        }
+    }
+}
+
+// Thread exit
+impl Bucket {
+    /// Hand every block to the top-level, as the thread that owns the bucket
+    /// is exiting. Blocks still in use wait there to be adopted by another
+    /// thread (`TopLevel::adopt`); the bucket ends up empty, ready for reuse.
+    pub fn abandon(&mut self) {
+        self.lists_mutex.lock();
+        // everything on these is in the bucket list as well
+        self.meshed_away_list.swap(ptr::null_mut(), Ordering::SeqCst);
+        self.maybe_mesh_list.swap(ptr::null_mut(), Ordering::SeqCst);
+        let mut free_list = self.maybe_free_list.swap(ptr::null_mut(), Ordering::SeqCst);
+        while !free_list.is_null() {
+            let free_list_ref = unsafe { &*free_list };
+            free_list = free_list_ref._maybe_next_free();
+            free_list_ref.flags.fetch_and(!block::BLOCK_FLAGS_MAYBE_FREE, Ordering::SeqCst);
+        }
+
+        let top_level = top_level::get();
+        let mut curr = self.active.swap(ptr::null_mut(), Ordering::SeqCst);
+        while !curr.is_null() {
+            let b_ref = unsafe { &mut *curr };
+            curr = mem::replace(&mut b_ref.next_in_bucket, ptr::null_mut());
+            b_ref.prev_in_bucket = ptr::null_mut();
+            b_ref.lock_free();
+            b_ref.flags.fetch_and(!block::BLOCK_FLAGS_MAYBE_MESH, Ordering::SeqCst);
+            // a block meshed away goes to the top-level when it's unmeshed
+            let orphaned = b_ref.prep_retired();
+            let header = unsafe { b_ref.get_segment().block_header(b_ref._segment_idx()) };
+            if orphaned {
+                top_level.receive_abandoned(b_ref.bucket_index(), header);
+            } else {
+                b_ref.flags.fetch_and(!block::BLOCK_FLAGS_FREE_LOCK, Ordering::SeqCst);
+            }
+        }
+        unsafe { self.lists_mutex.unlock() };
+    }
+}
+
+// Meshing
+impl Bucket {
+    /// Mesh the sparse blocks that were offered through `maybe_mesh`, pairing
+    /// them up with the SplitMesher, `MAX_MESH_CANDIDATES` at a time.
+    ///
+    /// Must be called by the thread that owns the bucket.
+    pub fn mesh(&mut self) -> MeshStats {
+        let this = self as *mut Bucket;
+        let mut stats = MeshStats::default();
+        let mut list = self.maybe_mesh_list.swap(ptr::null_mut(), Ordering::SeqCst);
+        // on the stack: this runs underneath the allocator
+        let mut candidates = [ptr::null_mut::<BlockHeader>(); MAX_MESH_CANDIDATES];
+        let mut pairs =
+            [(ptr::null_mut::<BlockHeader>(), ptr::null_mut()); MAX_MESH_CANDIDATES / 2];
+        while !list.is_null() {
+            // keep the blocks that are still ours and still sparse
+            let mut len = 0;
+            while !list.is_null() && len < MAX_MESH_CANDIDATES {
+                let list_ref = unsafe { &mut *list };
+                list = list_ref._maybe_next_mesh();
+                list_ref.flags.fetch_and(!block::BLOCK_FLAGS_MAYBE_MESH, Ordering::SeqCst);
+                if list_ref.is_mesh_candidate(this) {
+                    candidates[len] = list_ref;
+                    len += 1;
+                }
+            }
+            stats.candidates += len;
+            if !MESHING_SUPPORTED {
+                continue
+            }
+
+            let found = block::THREAD_RNG.with(|rng| {
+                mesh::split_mesher(
+                    &mut candidates[..len],
+                    &mut pairs,
+                    &mut *rng.borrow_mut(),
+                    SPLIT_MESHER_PROBES,
+                    |a, b| unsafe { &*a }.meshes_with(unsafe { &*b }),
+                )
+            });
+            stats.pairs += found;
+            for &(a, b) in &pairs[..found] {
+                let (a_ref, b_ref) = unsafe { (&mut *a, &mut *b) };
+                // a block that others were meshed into has to stay put
+                let src = if a_ref.mesh_into(b_ref) {
+                    a
+                } else if b_ref.mesh_into(a_ref) {
+                    b
+                } else {
+                    continue
+                };
+                self.retire(src);
+                stats.meshed += 1;
+            }
+        }
+        stats
+    }
+
+    /// Hand a block that was meshed away by another thread back to the owner,
+    /// which unlinks it the next time it goes looking for a block. Returns
+    /// false if the block isn't the bucket's any more: its thread exited.
+    pub fn meshed_away(&self, block_header: *mut BlockHeader) -> bool {
+        self.lists_mutex.lock();
+        let ours = self.owns(block_header);
+        if ours {
+            let mut curr = self.meshed_away_list.load(Ordering::SeqCst);
+            loop {
+                unsafe { &mut *block_header }._set_next_meshed_away(curr);
+                match self.meshed_away_list.compare_exchange_weak(
+                    curr,
+                    block_header,
+                    Ordering::SeqCst,
+                    Ordering::SeqCst,
+                ) {
+                    Ok(_) => break,
+                    Err(actual) => curr = actual,
+                }
+            }
+        }
+        unsafe { self.lists_mutex.unlock() };
+        ours
+    }
+
+    /// Offer an inactive block that's gone sparse for meshing, unless it's
+    /// been offered already or isn't the bucket's any more.
+    ///
+    /// Invariant(never bucket'maybe_mesh [@ block block])
+    pub fn maybe_mesh(&self, block_header: *mut BlockHeader) {
+        self.lists_mutex.lock();
+        let prev_flags = if self.owns(block_header) {
+            unsafe { &*block_header }
+                .flags
+                .fetch_or(block::BLOCK_FLAGS_MAYBE_MESH, Ordering::SeqCst)
+        } else {
+            block::BLOCK_FLAGS_MAYBE_MESH
+        };
+        if 0 == prev_flags & block::BLOCK_FLAGS_MAYBE_MESH {
+            let mut curr = self.maybe_mesh_list.load(Ordering::SeqCst);
+            loop {
+                unsafe { &mut *block_header }._set_maybe_next_mesh(curr);
+                match self.maybe_mesh_list.compare_exchange_weak(
+                    curr,
+                    block_header,
+                    Ordering::SeqCst,
+                    Ordering::SeqCst,
+                ) {
+                    Ok(_) => break,
+                    Err(actual) => curr = actual,
+                }
+            }
+        }
+        unsafe { self.lists_mutex.unlock() };
+    }
 }
 
 // Infrastructure
 impl Bucket {
     pub fn new() -> Bucket { Bucket::default() }
 
+    /// Returns false if the block isn't the bucket's any more: its thread
+    /// exited.
+    ///
     /// Invariant(never bucket'maybe_free [@ block block])
-    pub fn maybe_free(&mut self, block_header: *mut BlockHeader) {
-        let mut curr = self.maybe_free_list.load(Ordering::SeqCst);
-
-        loop {
-            unsafe { &mut *block_header }._set_maybe_next_free(curr);
-            match self.maybe_free_list.compare_exchange_weak(
-                curr,
-                block_header,
-                Ordering::SeqCst,
-                Ordering::SeqCst,
-            ) {
-                Ok(_) => break,
-                Err(actual) => curr = actual,
+    pub fn maybe_free(&self, block_header: *mut BlockHeader) -> bool {
+        self.lists_mutex.lock();
+        let ours = self.owns(block_header);
+        if ours {
+            let mut curr = self.maybe_free_list.load(Ordering::SeqCst);
+            loop {
+                unsafe { &mut *block_header }._set_maybe_next_free(curr);
+                match self.maybe_free_list.compare_exchange_weak(
+                    curr,
+                    block_header,
+                    Ordering::SeqCst,
+                    Ordering::SeqCst,
+                ) {
+                    Ok(_) => break,
+                    Err(actual) => curr = actual,
+                }
             }
         }
+        unsafe { self.lists_mutex.unlock() };
+        ours
+    }
+
+    fn owns(&self, block_header: *mut BlockHeader) -> bool {
+        unsafe { &*block_header }._bucket() as *const Bucket == self as *const Bucket
     }
 }
 
@@ -200,7 +416,9 @@ impl Default for Bucket {
             active: AtomicPtr::new(ptr::null_mut()),
             maybe_free_list: AtomicPtr::new(ptr::null_mut()),
             maybe_mesh_list: AtomicPtr::new(ptr::null_mut()),
+            meshed_away_list: AtomicPtr::new(ptr::null_mut()),
             count: AtomicUsize::new(0),
+            lists_mutex: <RawMutex as parking_lot::lock_api::RawMutex>::INIT,
         }
     }
 }
@@ -254,11 +472,39 @@ const fn semi_logarithmic_interval(lower_boundary: usize, upper_boundary: usize)
 
 #[cfg(test)]
 mod tests {
+    use std::ptr;
+    use std::sync::atomic::Ordering;
+
     use super::{
-        bucket_select, bucket_to_size, semi_logarithmic_interval, LARGE_BUCKETS,
+        bucket_select, bucket_to_size, semi_logarithmic_interval, Bucket, LARGE_BUCKETS,
         LARGE_OBJECT_BOUNDARY, NONTINY_SMALL_BUCKETS, SMALL_BUCKETS, SMALL_OBJECT_BOUNDARY,
         TINY_OBJECT_BOUNDARY, TINY_SMALL_BUCKETS,
     };
+    use crate::block::BlockHeader;
+
+    #[test]
+    fn bucket_list_unlinks_in_place() {
+        let mut a = BlockHeader::from_raw_parts(ptr::null_mut(), 0);
+        let mut b = BlockHeader::from_raw_parts(ptr::null_mut(), 1);
+        let mut c = BlockHeader::from_raw_parts(ptr::null_mut(), 2);
+        let (a, b, c) = (&mut a as *mut BlockHeader, &mut b as *mut _, &mut c as *mut _);
+        let mut bucket = Bucket::new();
+        for header in [a, b, c] {
+            bucket.push(header);
+        }
+        let links = |header: *mut BlockHeader| unsafe {
+            ((*header).prev_in_bucket, (*header).next_in_bucket)
+        };
+
+        bucket.unlink(b);
+        assert_eq!(links(c), (ptr::null_mut(), a));
+        assert_eq!(links(a), (c, ptr::null_mut()));
+        assert_eq!(links(b), (ptr::null_mut(), ptr::null_mut()));
+        bucket.unlink(a);
+        assert_eq!(links(c), (ptr::null_mut(), ptr::null_mut()));
+        assert_eq!(bucket.active.load(Ordering::SeqCst), c);
+    }
+
     #[test]
     fn bucket_tiny() {
         for size in 1..16 {
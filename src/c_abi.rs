@@ -0,0 +1,298 @@
+//! C allocation entry points, exported when built with `--features c-abi` so
+//! that the cdylib can be dropped in with `LD_PRELOAD`.
+//!
+//! Pointers that aura never handed out (memory the dynamic loader allocated
+//! before these symbols were bound) are ignored by `free` and report a usable
+//! size of 0. `realloc` can't make do without their size, so it passes them
+//! on to the `realloc` next in line, the C library's; failing that, it aborts.
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::{mem, process, ptr};
+
+use libc::{c_char, c_int, c_void, size_t};
+
+use super::api::{self, aura_alloc_aligned, aura_free, aura_usable_size, MAX_ALIGN};
+use super::constants::MB;
+use super::{segment_map, vm};
+
+/// `malloc` must return memory suitably aligned for any fundamental type.
+const MALLOC_ALIGN: usize = 16;
+
+#[cfg(target_os = "linux")]
+unsafe fn set_errno(err: c_int) { *libc::__errno_location() = err }
+#[cfg(target_os = "macos")]
+unsafe fn set_errno(err: c_int) { *libc::__error() = err }
+
+unsafe fn alloc_aligned(size: usize, align: usize) -> *mut c_void {
+    let obj = aura_alloc_aligned(size, align);
+    if obj.is_null() {
+        set_errno(libc::ENOMEM);
+    }
+    obj as *mut c_void
+}
+
+fn is_ours(ptr: *mut c_void) -> bool {
+    !ptr.is_null() && segment_map::contains(ptr as usize & !(4 * MB - 1))
+}
+
+type ReallocFn = unsafe extern "C" fn(*mut c_void, size_t) -> *mut c_void;
+
+/// The `realloc` that this one shadows, if there is one.
+unsafe fn next_realloc() -> Option<ReallocFn> {
+    // 0 until looked up; dlsym is too slow to ask every time
+    static NEXT: AtomicUsize = AtomicUsize::new(0);
+    let mut next = NEXT.load(Ordering::Relaxed);
+    if next == 0 {
+        next = libc::dlsym(libc::RTLD_NEXT, b"realloc\0".as_ptr() as *const c_char) as usize;
+        NEXT.store(next, Ordering::Relaxed);
+    }
+    (next != 0).then(|| mem::transmute::<usize, ReallocFn>(next))
+}
+
+#[cold]
+unsafe fn foreign_realloc(ptr: *mut c_void, size: size_t) -> *mut c_void {
+    match next_realloc() {
+        Some(next) => next(ptr, size),
+        None => {
+            eprintln!("aura: realloc of {:#?}, which isn't aura's, with no other realloc", ptr);
+            process::abort()
+        },
+    }
+}
+
+fn usable_size(ptr: *mut c_void) -> usize {
+    if !is_ours(ptr) {
+        return 0
+    }
+    aura_usable_size(ptr as *mut u8)
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn malloc(size: size_t) -> *mut c_void { alloc_aligned(size, MALLOC_ALIGN) }
+
+#[no_mangle]
+pub unsafe extern "C" fn free(ptr: *mut c_void) {
+    if is_ours(ptr) {
+        aura_free(ptr as *mut u8);
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn calloc(count: size_t, size: size_t) -> *mut c_void {
+    let total = match count.checked_mul(size) {
+        Some(total) => total,
+        None => {
+            set_errno(libc::ENOMEM);
+            return ptr::null_mut()
+        },
+    };
+    let obj = api::alloc_zeroed(total, MALLOC_ALIGN) as *mut c_void;
+    if obj.is_null() {
+        set_errno(libc::ENOMEM);
+    }
+    obj
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn realloc(ptr: *mut c_void, size: size_t) -> *mut c_void {
+    if ptr.is_null() {
+        return malloc(size)
+    }
+    if !is_ours(ptr) {
+        return foreign_realloc(ptr, size)
+    }
+    if size == 0 {
+        free(ptr);
+        return ptr::null_mut()
+    }
+    // like `GlobalAlloc::realloc`, moving an object that shrinks to a smaller
+    // size class, or giving back the pages past the end of a dedicated one
+    if let Some(resized) = api::resize_in_place(ptr as *mut u8, size, MALLOC_ALIGN) {
+        return resized as *mut c_void
+    }
+    let old_size = aura_usable_size(ptr as *mut u8);
+    let new_ptr = malloc(size);
+    if !new_ptr.is_null() {
+        ptr::copy_nonoverlapping(ptr as *const u8, new_ptr as *mut u8, old_size.min(size));
+        free(ptr);
+    }
+    new_ptr
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn reallocarray(
+    ptr: *mut c_void,
+    count: size_t,
+    size: size_t,
+) -> *mut c_void {
+    match count.checked_mul(size) {
+        Some(total) => realloc(ptr, total),
+        None => {
+            set_errno(libc::ENOMEM);
+            ptr::null_mut()
+        },
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn memalign(align: size_t, size: size_t) -> *mut c_void {
+    if !align.is_power_of_two() || align > MAX_ALIGN {
+        set_errno(libc::EINVAL);
+        return ptr::null_mut()
+    }
+    alloc_aligned(size, align.max(MALLOC_ALIGN))
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn aligned_alloc(align: size_t, size: size_t) -> *mut c_void {
+    memalign(align, size)
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn posix_memalign(
+    memptr: *mut *mut c_void,
+    align: size_t,
+    size: size_t,
+) -> c_int {
+    if !align.is_power_of_two()
+        || 0 != align % std::mem::size_of::<*mut c_void>()
+        || align > MAX_ALIGN
+    {
+        return libc::EINVAL
+    }
+    // posix_memalign reports failure through its return value, not errno
+    let obj = aura_alloc_aligned(size, align.max(MALLOC_ALIGN));
+    if obj.is_null() {
+        return libc::ENOMEM
+    }
+    *memptr = obj as *mut c_void;
+    0
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn valloc(size: size_t) -> *mut c_void { memalign(vm::page_size(), size) }
+
+#[no_mangle]
+pub unsafe extern "C" fn pvalloc(size: size_t) -> *mut c_void {
+    let page = vm::page_size();
+    match size.checked_add(page - 1) {
+        Some(padded) => memalign(page, padded & !(page - 1)),
+        None => {
+            set_errno(libc::ENOMEM);
+            ptr::null_mut()
+        },
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn malloc_usable_size(ptr: *mut c_void) -> size_t { usable_size(ptr) }
+
+#[cfg(test)]
+mod tests {
+    use std::hint::black_box;
+    use std::ptr;
+
+    use libc::c_void;
+
+    use super::*;
+    use crate::constants::KB;
+
+    #[test]
+    fn c_malloc_free() {
+        unsafe {
+            free(ptr::null_mut());
+            let p = malloc(100) as *mut u8;
+            assert!(!p.is_null());
+            assert_eq!(p as usize % MALLOC_ALIGN, 0);
+            assert!(malloc_usable_size(p as *mut c_void) >= 100);
+            ptr::write_bytes(p, 0xab, 100);
+            free(p as *mut c_void);
+        }
+    }
+
+    #[test]
+    fn c_calloc_overflow() {
+        unsafe {
+            // LLVM knows calloc's semantics and would fold the null check away
+            let calloc: unsafe extern "C" fn(size_t, size_t) -> *mut c_void = black_box(calloc);
+            assert!(calloc(usize::MAX / 2, 3).is_null());
+            assert_eq!(std::io::Error::last_os_error().raw_os_error(), Some(libc::ENOMEM));
+            assert!(reallocarray(ptr::null_mut(), usize::MAX / 2, 3).is_null());
+            assert_eq!(std::io::Error::last_os_error().raw_os_error(), Some(libc::ENOMEM));
+            let p = calloc(10, 10) as *mut u8;
+            assert!((0..100).all(|i| *p.add(i) == 0));
+            free(p as *mut c_void);
+        }
+    }
+
+    #[test]
+    fn c_realloc_preserves() {
+        unsafe {
+            let p = malloc(24) as *mut u8;
+            for i in 0..24 {
+                *p.add(i) = i as u8;
+            }
+            let q = realloc(p as *mut c_void, 2000) as *mut u8;
+            assert!((0..24).all(|i| *q.add(i) == i as u8));
+            assert!(realloc(q as *mut c_void, 0).is_null());
+        }
+    }
+
+    #[test]
+    fn c_realloc_shrinks() {
+        unsafe {
+            // a large object moves to a size class that fits
+            let p = malloc(256 * KB) as *mut u8;
+            ptr::write_bytes(p, 0xab, 256 * KB);
+            let q = realloc(p as *mut c_void, 1000) as *mut u8;
+            assert!(malloc_usable_size(q as *mut c_void) < 2 * KB);
+            assert!((0..1000).all(|i| *q.add(i) == 0xab));
+            free(q as *mut c_void);
+
+            // a huge one stays, with fewer pages
+            let p = malloc(16 * MB) as *mut u8;
+            ptr::write_bytes(p, 0xab, 16 * MB);
+            let q = realloc(p as *mut c_void, 6 * MB) as *mut u8;
+            assert_eq!(q, p);
+            assert!(malloc_usable_size(q as *mut c_void) < 7 * MB);
+            assert!((0..6 * MB).all(|i| *q.add(i) == 0xab));
+            free(q as *mut c_void);
+        }
+    }
+
+    #[test]
+    fn c_realloc_passes_foreign_pointers_on() {
+        unsafe {
+            // memory from the C library's own malloc, behind aura's
+            let name = b"malloc\0".as_ptr() as *const c_char;
+            let next_malloc: unsafe extern "C" fn(size_t) -> *mut c_void =
+                mem::transmute(libc::dlsym(libc::RTLD_NEXT, name));
+            let p = next_malloc(24) as *mut u8;
+            assert!(!is_ours(p as *mut c_void));
+            for i in 0..24 {
+                *p.add(i) = i as u8;
+            }
+            let q = realloc(p as *mut c_void, 2000) as *mut u8;
+            assert!(!q.is_null() && !is_ours(q as *mut c_void));
+            assert!((0..24).all(|i| *q.add(i) == i as u8));
+            next_realloc().unwrap()(q as *mut c_void, 0);
+        }
+    }
+
+    #[test]
+    fn c_aligned() {
+        unsafe {
+            let mut p: *mut c_void = ptr::null_mut();
+            assert_eq!(posix_memalign(&mut p, 3, 10), libc::EINVAL);
+            assert_eq!(posix_memalign(&mut p, 256, 10), 0);
+            assert_eq!(p as usize % 256, 0);
+            free(p);
+            let q = aligned_alloc(64, 100);
+            assert_eq!(q as usize % 64, 0);
+            free(q);
+            let r = valloc(100);
+            assert_eq!(r as usize % vm::page_size(), 0);
+            free(r);
+        }
+    }
+}
@@ -0,0 +1,79 @@
+//! Overflow detection (the `canary` feature). An object from a block has the
+//! size it was asked for recorded in the last word of its slot, and the
+//! `CANARY_BYTES` before that filled with `CANARY`; its size class is picked
+//! with room for both, and everything before them is the object's to use (see
+//! `aura_usable_size`). Freeing the object checks the canary, so a write past
+//! the end of that, by up to `CANARY_BYTES`, gets reported.
+
+use std::{mem, ptr, slice};
+
+use crate::block::BlockHeader;
+
+/// What the slack after an object is filled with.
+pub const CANARY: u8 = 0xca;
+/// How many canary bytes every object gets at least.
+pub const CANARY_BYTES: usize = 8;
+const WORD: usize = mem::size_of::<usize>();
+/// How much more than an object's size its slot needs: the canary, and the
+/// recorded size after it.
+pub const RESERVE: usize = CANARY_BYTES + WORD;
+
+/// How much of an object's slot in `block` is the object's.
+pub fn usable_size(block: &BlockHeader) -> usize { block._object_size() - RESERVE }
+
+/// Record that `object`, from `block`, was asked for with `size` bytes, and
+/// put the canary after the usable part of its slot.
+pub fn arm(object: *mut u8, size: usize, block: &BlockHeader) {
+    let usable = usable_size(block);
+    debug_assert!(size <= usable);
+    unsafe {
+        ptr::write_bytes(object.add(usable), CANARY, CANARY_BYTES);
+        (object.add(usable + CANARY_BYTES) as *mut usize).write(size);
+    }
+}
+
+/// The size `object` was asked for, as recorded by `arm`.
+fn requested_size(object: *mut u8, block: &BlockHeader) -> usize {
+    unsafe { (object.add(block._object_size() - WORD) as *const usize).read() }
+}
+
+/// Check that the canary after `object` is intact; if not, something wrote
+/// past the end of it, so report where and abort.
+pub fn check(object: *mut u8, block: &BlockHeader) {
+    let usable = usable_size(block);
+    let canary = unsafe { slice::from_raw_parts(object.add(usable), CANARY_BYTES) };
+    if let Some(offset) = canary.iter().position(|&byte| byte != CANARY) {
+        overflow(object, Some(usable + offset), block)
+    }
+    if requested_size(object, block) > usable {
+        // the recorded size itself was written over
+        overflow(object, None, block)
+    }
+}
+
+#[cold]
+fn overflow(object: *mut u8, at: Option<usize>, block: &BlockHeader) -> ! {
+    let segment = block.get_segment() as *const _;
+    match at {
+        Some(offset) => eprintln!(
+            "aura: write past the end of {:#?} ({} bytes, {} usable) at +{}, in size class {} \
+             of block {:#?} in segment {:#?}",
+            object,
+            requested_size(object, block),
+            usable_size(block),
+            offset,
+            block._object_size(),
+            block as *const BlockHeader,
+            segment
+        ),
+        None => eprintln!(
+            "aura: write past the end of {:#?} over its recorded size, in size class {} of block \
+             {:#?} in segment {:#?}",
+            object,
+            block._object_size(),
+            block as *const BlockHeader,
+            segment
+        ),
+    }
+    std::process::abort()
+}
@@ -1,18 +1,76 @@
 use std::default::Default;
-use std::ptr;
 use std::sync::atomic::*;
+use std::{process, ptr};
+
+/// How a free object's link to the next one is stored in its first word.
+/// With the `hardened` feature, links are XORed with a random key of the
+/// block's, and a link that doesn't decode to somewhere inside the block means
+/// a freed object was written to: that aborts, rather than letting the write
+/// pick what the allocator hands out next. Without it, links are stored as
+/// they are.
+#[derive(Clone, Copy)]
+pub struct LinkCodec {
+    #[cfg(feature = "hardened")]
+    key: usize,
+    #[cfg(feature = "hardened")]
+    start: usize,
+    #[cfg(feature = "hardened")]
+    end: usize,
+}
+
+impl LinkCodec {
+    /// For a block whose body is `[start, end)`.
+    #[inline]
+    pub fn new(key: usize, start: *mut u8, end: *mut u8) -> LinkCodec {
+        #[cfg(feature = "hardened")]
+        return LinkCodec { key, start: start as usize, end: end as usize };
+        #[cfg(not(feature = "hardened"))]
+        LinkCodec {}
+    }
+
+    #[inline]
+    pub fn encode<T>(&self, next: *mut T) -> *mut T {
+        #[cfg(feature = "hardened")]
+        return (next as usize ^ self.key) as *mut T;
+        #[cfg(not(feature = "hardened"))]
+        next
+    }
+
+    #[inline]
+    pub fn decode<T>(&self, link: *mut T) -> *mut T {
+        #[cfg(feature = "hardened")]
+        {
+            let next = link as usize ^ self.key;
+            if 0 != next && !(self.start..self.end).contains(&next) {
+                corrupted(link as usize, next)
+            }
+            return next as *mut T
+        }
+        #[cfg(not(feature = "hardened"))]
+        link
+    }
+}
+
+#[cold]
+fn corrupted(link: usize, next: usize) -> ! {
+    eprintln!(
+        "aura: free list corrupted: link {:#x} decodes to {:#x}, outside its block",
+        link, next
+    );
+    process::abort()
+}
 
 pub trait AnyFreeList {
     fn is_empty(&self) -> bool;
 }
 
 pub trait FreeListPush<T>: AnyFreeList {
-    fn push(&mut self, ptr: *mut T);
+    fn push(&mut self, ptr: *mut T, codec: LinkCodec);
     fn swap(&mut self, new_ptr: *mut T) -> *mut T;
 }
 
 pub trait FreeListPop<T>: AnyFreeList {
-    fn pop(&mut self) -> *mut T;
+    fn pop(&mut self, codec: LinkCodec) -> *mut T;
 }
 
 #[repr(C)]
@@ -32,10 +90,10 @@ impl<T> AnyFreeList for AtomicPushFreeList<T> {
 }
 
 impl<T: std::fmt::Debug> FreeListPush<T> for AtomicPushFreeList<T> {
-    fn push(&mut self, ptr: *mut T) {
+    fn push(&mut self, ptr: *mut T, codec: LinkCodec) {
         let mut curr = self.0.load(Ordering::SeqCst);
         loop {
-            unsafe { *{ ptr as *mut *mut T } = curr };
+            unsafe { *{ ptr as *mut *mut T } = codec.encode(curr) };
             #[cfg_attr(rustfmt, rustfmt_skip)]
             match self.0.compare_exchange_weak(curr, ptr, Ordering::SeqCst, Ordering::SeqCst)
             {
@@ -67,9 +125,9 @@ impl<T> AnyFreeList for BiFreeList<T> {
 }
 
 impl<T> FreeListPush<T> for BiFreeList<T> {
-    fn push(&mut self, ptr: *mut T) {
+    fn push(&mut self, ptr: *mut T, codec: LinkCodec) {
         unsafe {
-            *{ ptr as *mut *mut T } = self.0;
+            *{ ptr as *mut *mut T } = codec.encode(self.0);
         }
         self.0 = ptr;
     }
@@ -82,10 +140,10 @@ impl<T> FreeListPush<T> for BiFreeList<T> {
 }
 
 impl<T> FreeListPop<T> for BiFreeList<T> {
-    fn pop(&mut self) -> *mut T {
+    fn pop(&mut self, codec: LinkCodec) -> *mut T {
         let r = self.0;
         if !r.is_null() {
-            self.0 = unsafe { *{ r as *mut *mut T } };
+            self.0 = codec.decode(unsafe { *{ r as *mut *mut T } });
         }
         r
     }
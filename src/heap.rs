@@ -1,12 +1,32 @@
-use std::cell::UnsafeCell;
+use std::cell::{Cell, UnsafeCell};
 use std::mem::{self, MaybeUninit};
+use std::num::NonZeroU64;
 use std::ptr;
+use std::sync::atomic::{AtomicU64, Ordering};
 
+use libc::c_void;
+use parking_lot::Mutex;
+
+#[cfg(any(feature = "canary", feature = "debug-uaf"))]
+use super::api;
 use super::bucket::{bucket_select, Bucket, BUCKETS};
+#[cfg(feature = "canary")]
+use super::canary;
+use super::mesh::MeshStats;
+#[cfg(feature = "debug-uaf")]
+use super::quarantine;
+use super::segment::SegmentHeader;
+use super::vm;
 
+/// A thread's buckets. Heaps are never unmapped: blocks point at their
+/// buckets, and other threads follow those pointers to hand blocks back. When
+/// a thread exits, its heap gives all of its blocks to the top-level and waits
+/// in a pool for the next thread.
 #[repr(C)]
 pub struct Heap {
     buckets: [UnsafeCell<Bucket>; BUCKETS],
+    // HeapPool
+    next_heap: *mut Heap,
 }
 
 impl Heap {
@@ -20,11 +40,47 @@ impl Heap {
                 }
                 unsafe { mem::transmute::<_, _>(data) }
             },
+            next_heap: ptr::null_mut(),
+        }
+    }
+
+    /// A new heap, mapped straight from the OS (it can't come from ourselves).
+    fn create() -> &'static mut Heap {
+        let size = (mem::size_of::<Heap>() + vm::page_size() - 1) & !(vm::page_size() - 1);
+        let addr = unsafe {
+            libc::mmap(
+                ptr::null_mut(),
+                size,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
+                -1,
+                0,
+            )
+        };
+        if addr == libc::MAP_FAILED {
+            panic!("couldn't map a thread heap");
+        }
+        let heap = addr as *mut Heap;
+        unsafe {
+            ptr::write(heap, Heap::new());
+            &mut *heap
+        }
+    }
+
+    /// Hand every bucket's blocks to the top-level, for the thread is exiting.
+    fn abandon(&self) {
+        for bucket in &self.buckets[..] {
+            unsafe { &mut *bucket.get() }.abandon();
         }
     }
 
     pub fn alloc(&self, size: usize) -> *mut u8 {
-        let bucket_idx = bucket_select(size);
+        let bucket_idx = bucket_select(reserved_size(size));
+        if bucket_idx >= BUCKETS {
+            // huge: past the largest size class, the object gets a segment of
+            // its own, mapped straight from the OS
+            return SegmentHeader::new_dedicated(size, vm::page_size()).unwrap_or(ptr::null_mut())
+        }
         // println!(
         //     "size={}, bucket={} [{}, {})",
         //     size,
@@ -32,14 +88,114 @@ impl Heap {
         //     super::bucket::bucket_to_size(bucket_idx),
         //     super::bucket::bucket_to_size(bucket_idx + 1)
         // );
+        let object = self.alloc_bucket(bucket_idx);
+        #[cfg(feature = "canary")]
+        if !object.is_null() {
+            canary::arm(object, size, unsafe { api::find_block_for_object(object) });
+        }
+        object
+    }
+
+    /// Allocate from a particular bucket, for callers that pick the size class
+    /// themselves (e.g. to get a stride with a particular alignment).
+    pub fn alloc_bucket(&self, bucket_idx: usize) -> *mut u8 {
+        debug_assert!(bucket_idx < BUCKETS);
         unsafe { &mut *self.buckets.get_unchecked(bucket_idx).get() }.alloc(bucket_idx)
     }
+
+    /// Mesh the sparse blocks of every bucket.
+    pub fn mesh(&self) -> MeshStats {
+        let mut stats = MeshStats::default();
+        for bucket in &self.buckets[..] {
+            stats += unsafe { &mut *bucket.get() }.mesh();
+        }
+        stats
+    }
+}
+
+/// How much of its slot an object of `size` takes: with `canary`, there has to
+/// be room for the canary as well.
+pub fn reserved_size(size: usize) -> usize {
+    #[cfg(feature = "canary")]
+    return size + canary::RESERVE;
+    #[cfg(not(feature = "canary"))]
+    size
+}
+
+/// Intrusive list of the heaps of exited threads.
+struct HeapPool {
+    head: *mut Heap,
+}
+
+unsafe impl Send for HeapPool {}
+
+lazy_static! {
+    static ref HEAP_POOL: Mutex<HeapPool> = Mutex::new(HeapPool { head: ptr::null_mut() });
+    // a pthread key rather than a Drop impl on the thread local: the key's
+    // destructor also runs on threads std doesn't know about, and registering
+    // it doesn't allocate
+    static ref HEAP_KEY: libc::pthread_key_t = {
+        let mut key = 0;
+        if 0 != unsafe { libc::pthread_key_create(&mut key, Some(abandon_thread_heap)) } {
+            panic!("couldn't create the thread heap key");
+        }
+        key
+    };
 }
 
 thread_local! {
-    pub static THREAD_HEAP: Heap = Heap::new();
+    static THREAD_HEAP: Cell<*mut Heap> = Cell::new(ptr::null_mut());
+    static THREAD_ID: Cell<u64> = Cell::new(0);
+}
+
+static NEXT_THREAD_ID: AtomicU64 = AtomicU64::new(1);
+
+/// Identifies the calling thread for block ownership. Unlike
+/// `thread::current().id()` this never allocates, so it's usable from the
+/// allocator on threads std doesn't know about yet (e.g. under the C shim).
+pub fn thread_id() -> NonZeroU64 {
+    THREAD_ID.with(|id| {
+        if 0 == id.get() {
+            id.set(NEXT_THREAD_ID.fetch_add(1, Ordering::Relaxed));
+        }
+        unsafe { NonZeroU64::new_unchecked(id.get()) }
+    })
 }
 
 pub fn thread_heap() -> &'static Heap {
-    THREAD_HEAP.with(|heap| unsafe { mem::transmute::<&'_ Heap, &'static Heap>(heap) })
+    THREAD_HEAP.with(|heap| {
+        if heap.get().is_null() {
+            heap.set(adopt_heap());
+        }
+        unsafe { &*heap.get() }
+    })
+}
+
+/// Take a heap from the pool, or a new one, for the calling thread.
+fn adopt_heap() -> *mut Heap {
+    let pooled = {
+        let mut pool = HEAP_POOL.lock();
+        let heap = pool.head;
+        if !heap.is_null() {
+            pool.head = mem::replace(unsafe { &mut (*heap).next_heap }, ptr::null_mut());
+        }
+        heap
+    };
+    let heap = if pooled.is_null() { Heap::create() as *mut Heap } else { pooled };
+    // also after the destructor ran, if the thread allocates again while
+    // exiting: destructors are rerun for keys that were set again
+    unsafe { libc::pthread_setspecific(*HEAP_KEY, heap as *const c_void) };
+    heap
+}
+
+extern "C" fn abandon_thread_heap(heap: *mut c_void) {
+    let heap = unsafe { &mut *(heap as *mut Heap) };
+    // freed as this thread's own objects, while its blocks are still its own
+    #[cfg(feature = "debug-uaf")]
+    quarantine::flush(api::release_to_block);
+    THREAD_HEAP.with(|thread_heap| thread_heap.set(ptr::null_mut()));
+    heap.abandon();
+    let mut pool = HEAP_POOL.lock();
+    heap.next_heap = pool.head;
+    pool.head = heap;
 }
@@ -7,11 +7,14 @@
 #![feature(const_maybe_uninit_assume_init, inline_const, const_generics, const_evaluatable_checked)]
 #![feature(option_result_unwrap_unchecked)]
 #![feature(format_args_nl)]
+#![feature(thread_local_const_init)]
+#![cfg_attr(test, feature(bench_black_box))]
 
 #[macro_use]
 extern crate lazy_static;
 
 extern crate libc;
+#[cfg(target_os = "macos")]
 extern crate mach;
 extern crate num_cpus;
 extern crate parking_lot;
@@ -23,6 +26,13 @@ use std::fmt;
 #[derive(Debug)]
 pub enum Error {
     OutOfMemory,
+    /// The operation can't be expressed on this platform's VM backend (e.g.
+    /// aliasing pages without shared file backing).
+    Unsupported,
+    /// Something that isn't the start of an object aura handed out was freed.
+    InvalidFree(usize),
+    /// An object was freed again without having been handed out since.
+    DoubleFree(usize),
     Generic(String),
 }
 
@@ -30,11 +40,23 @@ impl fmt::Display for Error {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
             Error::OutOfMemory => write!(f, "out of memory"),
+            Error::Unsupported => write!(f, "operation not supported by the VM backend"),
+            Error::InvalidFree(object) => write!(f, "free of {:#x}, which isn't an object", object),
+            Error::DoubleFree(object) => write!(f, "double free of {:#x}", object),
             Error::Generic(s) => write!(f, "{}", s),
         }
     }
 }
 
+/// Record an alloc or free on a block in the calling thread's trace; compiles
+/// to nothing without the `trace` feature.
+macro_rules! trace {
+    ($kind: ident, $block: expr, $prev_count: expr) => {
+        #[cfg(feature = "trace")]
+        $crate::trace::record($crate::trace::TraceKind::$kind, $block as usize, $prev_count);
+    };
+}
+
 mod block;
 // don't use:
 //mod raw_pool;
@@ -44,16 +66,46 @@ pub mod constants {
     pub const GB: usize = MB * 1024;
 }
 pub mod api;
+#[cfg(feature = "c-abi")]
+mod c_abi;
 mod top_level;
 
-pub use api::{aura_alloc, aura_free};
+#[cfg(feature = "hardened")]
+pub use api::aura_set_block_guards;
+#[cfg(feature = "debug-uaf")]
+pub use api::aura_set_quarantine_bytes;
+pub use api::{
+    aura_alloc, aura_alloc_aligned, aura_calloc, aura_free, aura_free_sized, aura_mesh, aura_purge,
+    aura_realloc, aura_scavenger_configure, aura_scavenger_pause, aura_scavenger_resume,
+    aura_scavenger_start, aura_scavenger_stats, aura_scavenger_stop, aura_set_purge_delay,
+    aura_set_segment_cache, aura_set_top_level_shards, aura_try_free, aura_usable_size, Aura,
+};
+#[cfg(feature = "trace")]
+pub use api::{aura_trace_dump, aura_trace_events};
+#[cfg(feature = "canary")]
+pub use canary::{CANARY, CANARY_BYTES};
+pub use mesh::MeshStats;
+#[cfg(feature = "debug-uaf")]
+pub use quarantine::{DEFAULT_QUARANTINE_BYTES, POISON};
+pub use scavenger::{ScavengerConfig, ScavengerStats};
+#[cfg(feature = "trace")]
+pub use trace::{TraceEvent, TraceKind, TRACE_EVENTS};
 
+mod barrier;
 mod bucket;
+#[cfg(feature = "canary")]
+mod canary;
 mod free_list;
 mod heap;
 mod mesh;
+#[cfg(feature = "debug-uaf")]
+mod quarantine;
+mod scavenger;
 mod segment;
+mod segment_map;
 mod shuffle;
+#[cfg(feature = "trace")]
+mod trace;
 // pub for some statistics
 mod util;
 mod vm;
@@ -62,14 +114,21 @@ mod vm;
 mod tests {
     extern crate crossbeam_channel;
 
+    use std::alloc::{GlobalAlloc, Layout};
     use std::sync::atomic::{AtomicUsize, Ordering};
     use std::sync::Arc;
     use std::{mem, panic, process, thread};
 
     use rand::prelude::*;
 
-    use crate::api::{aura_alloc, aura_free};
-    use crate::constants::KB;
+    use crate::api::{
+        aura_alloc, aura_alloc_aligned, aura_calloc, aura_free, aura_free_sized, aura_mesh,
+        aura_realloc, aura_usable_size, Aura,
+    };
+    use crate::bucket::{
+        bucket_select, bucket_to_size, BUCKETS, LARGE_OBJECT_BOUNDARY, SMALL_BUCKETS,
+    };
+    use crate::constants::{GB, KB, MB};
     use crate::top_level;
 
     enum EncounterCategorization {
@@ -137,6 +196,231 @@ mod tests {
         println!("ok");
     }
 
+    #[test]
+    fn realloc_in_place_and_move() {
+        let obj = aura_alloc(100);
+        for i in 0..100 {
+            unsafe { *obj.add(i) = i as u8 };
+        }
+        // still fits in what's usable of the slot: same pointer
+        let slot = aura_usable_size(obj);
+        assert_eq!(aura_realloc(obj, slot), obj);
+        assert_eq!(aura_realloc(obj, 10), obj);
+
+        let moved = aura_realloc(obj, 4 * KB);
+        assert_ne!(moved, obj);
+        assert!((0..100).all(|i| unsafe { *moved.add(i) } == i as u8));
+
+        assert!(aura_realloc(moved, 0).is_null());
+        let fresh = aura_realloc(std::ptr::null_mut(), 16);
+        assert!(!fresh.is_null());
+        aura_free(fresh);
+    }
+
+    #[test]
+    fn usable_and_sized_free() {
+        assert_eq!(aura_usable_size(std::ptr::null_mut()), 0);
+        for &size in &[1, 24, 100, 500, 4 * KB] {
+            let obj = aura_alloc(size);
+            let usable = aura_usable_size(obj);
+            // with canaries, the end of the slot isn't the object's
+            let reserve = crate::heap::reserved_size(size) - size;
+            let expected = bucket_to_size(bucket_select(size + reserve) + 1) - reserve;
+            assert_eq!(usable, expected);
+            assert!(usable >= size);
+            unsafe { std::ptr::write_bytes(obj, 0xcd, usable) };
+            aura_free_sized(obj, size);
+        }
+        let obj = aura_alloc_aligned(100, 64 * KB);
+        assert!(aura_usable_size(obj) >= 100);
+        aura_free_sized(obj, 100);
+        aura_free_sized(std::ptr::null_mut(), 24);
+        aura_free(std::ptr::null_mut());
+
+        // an object reallocated smaller is freed with its new size
+        let layout = Layout::from_size_align(1000, 8).unwrap();
+        for &size in &[900, 100, 8] {
+            unsafe {
+                let obj = Aura.realloc(Aura.alloc(layout), layout, size);
+                Aura.dealloc(obj, Layout::from_size_align(size, 8).unwrap());
+            }
+        }
+    }
+
+    #[test]
+    fn calloc_zeroes_fresh_and_recycled() {
+        assert!(aura_calloc(usize::MAX / 2, 3).is_null());
+        for &size in &[8, 48, 700, 3 * KB] {
+            // dirty a batch of slots and give them back, so that some of the
+            // calloc'd slots below are recycled ones
+            let dirty = (0..64).map(|_| aura_alloc(size)).collect::<Vec<_>>();
+            for &obj in &dirty {
+                unsafe { std::ptr::write_bytes(obj, 0xee, size) };
+            }
+            dirty.into_iter().for_each(aura_free);
+
+            let objs = (0..256).map(|_| aura_calloc(size / 4, 4)).collect::<Vec<_>>();
+            for &obj in &objs {
+                assert!((0..size).all(|i| unsafe { *obj.add(i) } == 0), "size {}", size);
+            }
+            objs.into_iter().for_each(aura_free);
+        }
+        let big = aura_calloc(1, 3 * MB);
+        assert!((0..3 * MB).step_by(4 * KB).all(|i| unsafe { *big.add(i) } == 0));
+        aura_free(big);
+    }
+
+    #[test]
+    fn large_every_size() {
+        // every size up to the largest size class, keeping a window of objects
+        // live so that blocks fill up, get released, and get reformatted
+        let mut live = std::collections::VecDeque::new();
+        // with canaries, the largest sizes need a dedicated segment
+        for size in 1..LARGE_OBJECT_BOUNDARY - crate::heap::reserved_size(0) {
+            let obj = aura_alloc(size);
+            assert!(!obj.is_null(), "size {}", size);
+            let usable = aura_usable_size(obj);
+            assert!(usable >= size);
+            let block = unsafe { crate::api::find_block_for_object(obj) };
+            let block_end = block.base() as usize + block.get_segment().block_size();
+            assert!(obj as usize + usable <= block_end, "size {} overruns its block", size);
+            unsafe {
+                *obj = size as u8;
+                *obj.add(size - 1) = size as u8;
+            }
+            live.push_back((obj, size));
+            if live.len() > 32 {
+                let (obj, size) = live.pop_front().unwrap();
+                assert_eq!(unsafe { *obj.add(size - 1) }, size as u8);
+                aura_free(obj);
+            }
+        }
+        live.into_iter().for_each(|(obj, _)| aura_free(obj));
+    }
+
+    #[test]
+    fn large_buckets_reuse_blocks() {
+        for bucket in SMALL_BUCKETS..BUCKETS {
+            let size = bucket_to_size(bucket);
+            let objs = (0..20).map(|_| aura_alloc(size)).collect::<Vec<_>>();
+            for &obj in &objs {
+                unsafe { std::ptr::write_bytes(obj, 0x5a, size) };
+            }
+            objs.into_iter().for_each(aura_free);
+        }
+    }
+
+    #[test]
+    fn huge_dedicated_segments() {
+        for &size in &[LARGE_OBJECT_BOUNDARY, LARGE_OBJECT_BOUNDARY + 1, 5 * MB, 100 * MB] {
+            let obj = aura_alloc(size);
+            assert!(!obj.is_null(), "size {}", size);
+            // rounded up to the page, not to a power of two
+            let usable = aura_usable_size(obj);
+            assert!(usable >= size && usable < size + crate::vm::page_size(), "size {}", size);
+            unsafe {
+                *obj = 1;
+                *obj.add(size - 1) = 1;
+            }
+            let grown = aura_realloc(obj, size + 4 * MB);
+            assert_eq!(unsafe { *grown }, 1);
+            aura_free(grown);
+        }
+    }
+
+    #[test]
+    fn huge_multi_gigabyte() {
+        // only the touched pages get backed
+        for _ in 0..2 {
+            let size = 3 * GB + 123;
+            let obj = aura_alloc(size);
+            assert!(!obj.is_null());
+            unsafe {
+                *obj = 1;
+                *obj.add(size / 2) = 2;
+                *obj.add(size - 1) = 3;
+                assert_eq!(*obj.add(size - 1), 3);
+            }
+            aura_free_sized(obj, size);
+        }
+    }
+
+    #[test]
+    fn mesh_keeps_objects() {
+        // fill a few dozen blocks, then free all but a handful of objects in
+        // each so that most blocks mesh
+        let size = 1000;
+        let objs = (0..64 * 64).map(|_| aura_alloc(size)).collect::<Vec<_>>();
+        for (i, &obj) in objs.iter().enumerate() {
+            unsafe { std::ptr::write_bytes(obj, i as u8, size) };
+        }
+        let mut kept = Vec::new();
+        for (i, obj) in objs.into_iter().enumerate() {
+            if i % 17 == 0 {
+                kept.push((i, obj));
+            } else {
+                aura_free(obj);
+            }
+        }
+        let stats = aura_mesh();
+        assert!(stats.candidates > 0);
+        assert!(stats.meshed <= stats.pairs && 2 * stats.pairs <= stats.candidates);
+        if crate::mesh::MESHING_SUPPORTED {
+            assert!(stats.meshed > 0);
+        }
+        for &(i, obj) in &kept {
+            assert!(aura_usable_size(obj) >= size);
+            assert!((0..size).all(|j| unsafe { *obj.add(j) } == i as u8), "object {} changed", i);
+            // writes through either alias land in the same place
+            unsafe { *obj = !(i as u8) };
+        }
+        for &(i, obj) in &kept {
+            assert_eq!(unsafe { *obj }, !(i as u8));
+        }
+        kept.into_iter().for_each(|(_, obj)| aura_free(obj));
+        // blocks that were meshed away get their own pages back once emptied
+        let again = (0..64 * 64).map(|_| aura_calloc(1, size)).collect::<Vec<_>>();
+        assert!(again.iter().all(|&obj| unsafe { *obj.add(size - 1) } == 0));
+        again.into_iter().for_each(aura_free);
+    }
+
+    #[test]
+    fn aligned_every_bucket() {
+        let mut align = 8;
+        while align <= crate::vm::page_size() {
+            for bucket in 0..BUCKETS {
+                let size = bucket_to_size(bucket);
+                let obj = aura_alloc_aligned(size, align);
+                assert!(!obj.is_null());
+                assert_eq!(obj as usize % align, 0, "size {} align {}", size, align);
+                unsafe {
+                    *obj = 1;
+                    *obj.add(size - 1) = 1;
+                }
+                aura_free(obj);
+            }
+            align *= 2;
+        }
+    }
+
+    #[test]
+    fn aligned_dedicated() {
+        for &align in &[64 * KB, MB, 2 * MB] {
+            for &size in &[1, 100 * KB, 5 * MB] {
+                let obj = aura_alloc_aligned(size, align);
+                assert!(!obj.is_null());
+                assert_eq!(obj as usize % align, 0);
+                unsafe {
+                    *obj = 1;
+                    *obj.add(size - 1) = 1;
+                }
+                assert!(aura_realloc(obj, size) == obj);
+                aura_free(obj);
+            }
+        }
+        assert!(aura_alloc_aligned(1, 4 * MB).is_null());
+    }
+
     #[test]
     fn stress_test_mamd() {
         println!("Testing many allocator threads, many deallocator threads...");
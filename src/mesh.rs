@@ -1,8 +1,12 @@
 use std::intrinsics;
 use std::iter::Iterator;
 use std::mem::{self, MaybeUninit};
+use std::ops::AddAssign;
 use std::sync::atomic::*;
 
+use rand::seq::SliceRandom;
+use rand::Rng;
+
 #[repr(transparent)]
 #[derive(Debug)]
 pub struct MeshMask<const N: usize>([AtomicU64; N]);
@@ -27,6 +31,9 @@ impl<const N: usize> MeshMask<N> {
     pub fn reset(&mut self, idx: usize) {
         self.0[idx / 64].fetch_and(!(1u64 << (idx % 64)), Ordering::SeqCst);
     }
+    pub fn test(&self, idx: usize) -> bool {
+        0u64 != (self.0[idx / 64].load(Ordering::SeqCst) & (1u64 << (idx % 64)))
+    }
     pub fn test_reset(&mut self, idx: usize) -> bool {
         let mask = 1u64 << (idx % 64);
         0u64 != (self.0[idx / 64].fetch_and(!mask, Ordering::SeqCst) & mask)
@@ -90,6 +97,119 @@ pub fn meshes_with<const N: usize>(lhs: &MeshMask<N>, rhs: &MeshMask<N>) -> bool
     r
 }
 
+/// Meshing needs to remap pages, which the fallback VM backend can't do.
+pub const MESHING_SUPPORTED: bool = cfg!(any(target_os = "linux", target_os = "macos"));
+
+/// Whether a block with `allocated` of its `count` slots live is worth trying
+/// to mesh. Two blocks with k random slots out of n each are disjoint with
+/// probability about e^(-k²/n), so past k = sqrt(n) meshes are too rare to
+/// look for.
+pub fn is_sparse(count: usize, allocated: usize) -> bool {
+    allocated > 0 && allocated * allocated <= count
+}
+
+/// Whether meshing a set of blocks (`count` slots each, `allocated` live)
+/// could possibly release one: their live objects have to fit in one block
+/// fewer.
 pub fn should_mesh(count: usize, allocated: &[usize]) -> bool {
-    unimplemented!();
+    allocated.len() >= 2 && allocated.iter().sum::<usize>() <= count * (allocated.len() - 1)
+}
+
+/// Most candidate blocks a single round of the SplitMesher looks at; the
+/// search state lives on the stack.
+pub const MAX_MESH_CANDIDATES: usize = 256;
+/// Offsets the SplitMesher probes each left-half block at (t in the paper).
+pub const SPLIT_MESHER_PROBES: usize = 64;
+
+/// Outcome of a meshing pass.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct MeshStats {
+    /// Sparse blocks that were considered.
+    pub candidates: usize,
+    /// Disjoint pairs the search found.
+    pub pairs: usize,
+    /// Pairs that were actually meshed, i.e. blocks released.
+    pub meshed: usize,
+}
+
+impl AddAssign for MeshStats {
+    fn add_assign(&mut self, other: MeshStats) {
+        self.candidates += other.candidates;
+        self.pairs += other.pairs;
+        self.meshed += other.meshed;
+    }
+}
+
+/// SplitMesher (Mesh, Powers et al. 2019): shuffle the candidates, split them
+/// into two halves, and try pairing the i-th block on the left with the
+/// (i + k)-th on the right for `probes` offsets k. Every candidate ends up in
+/// at most one pair. Found pairs are written to `pairs`; returns how many.
+pub fn split_mesher<T: Copy, R: Rng>(
+    candidates: &mut [T],
+    pairs: &mut [(T, T)],
+    rng: &mut R,
+    probes: usize,
+    mut meshable: impl FnMut(T, T) -> bool,
+) -> usize {
+    debug_assert!(candidates.len() <= MAX_MESH_CANDIDATES);
+    debug_assert!(pairs.len() >= candidates.len() / 2);
+    candidates.shuffle(rng);
+    let half = candidates.len() / 2;
+    let (left, right) = candidates.split_at(half);
+    let mut left_paired = [false; MAX_MESH_CANDIDATES / 2];
+    let mut right_paired = [false; MAX_MESH_CANDIDATES / 2];
+    let mut found = 0;
+    for k in 0..probes.min(half) {
+        for i in 0..half {
+            let j = (i + k) % half;
+            if !left_paired[i] && !right_paired[j] && meshable(left[i], right[j]) {
+                pairs[found] = (left[i], right[j]);
+                found += 1;
+                left_paired[i] = true;
+                right_paired[j] = true;
+            }
+        }
+    }
+    found
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::SeedableRng;
+    use rand_xoshiro::Xoshiro256StarStar;
+
+    use super::{split_mesher, MAX_MESH_CANDIDATES, SPLIT_MESHER_PROBES};
+
+    fn run(candidates: &mut [u64], meshable: impl FnMut(u64, u64) -> bool) -> Vec<(u64, u64)> {
+        let mut rng = Xoshiro256StarStar::seed_from_u64(0x5eed);
+        let mut pairs = [(0, 0); MAX_MESH_CANDIDATES / 2];
+        let found = split_mesher(candidates, &mut pairs, &mut rng, SPLIT_MESHER_PROBES, meshable);
+        pairs[..found].to_vec()
+    }
+
+    #[test]
+    fn split_mesher_pairs_each_once() {
+        // pairwise disjoint: everything pairs up
+        let mut candidates = (0..64).map(|i| 1u64 << i).collect::<Vec<_>>();
+        let pairs = run(&mut candidates, |a, b| 0 == a & b);
+        assert_eq!(pairs.len(), 32);
+        let seen = pairs.iter().fold(0u64, |seen, &(a, b)| {
+            assert_eq!(0, seen & (a | b));
+            seen | a | b
+        });
+        assert_eq!(seen, !0u64);
+
+        // nothing is disjoint
+        candidates.iter_mut().for_each(|c| *c |= 1);
+        assert!(run(&mut candidates, |a, b| 0 == a & b).is_empty());
+    }
+
+    #[test]
+    fn split_mesher_finds_sparse_matches() {
+        // only candidates of opposite parity mesh; the probes still find most
+        let mut candidates = (0..MAX_MESH_CANDIDATES as u64).collect::<Vec<_>>();
+        let pairs = run(&mut candidates, |a, b| (a ^ b) & 1 == 1);
+        assert!(pairs.len() >= MAX_MESH_CANDIDATES / 4, "found {}", pairs.len());
+        assert!(pairs.iter().all(|&(a, b)| (a ^ b) & 1 == 1));
+    }
 }
@@ -0,0 +1,127 @@
+//! Use-after-free detection (the `debug-uaf` feature). A freed object is filled
+//! with `POISON` and held back in a quarantine of the freeing thread, FIFO and
+//! bounded by bytes, before it really goes back to its block; every free slot
+//! is kept poisoned, so when one is handed out again a write to it while it
+//! was free shows as poison that isn't intact. Nothing here allocates.
+
+use std::cell::UnsafeCell;
+use std::ptr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::block::BlockHeader;
+use crate::constants::MB;
+
+/// What free slots are filled with. Their first word is left to the free list
+/// link.
+pub const POISON: u8 = 0xdb;
+/// How many freed bytes a thread holds back unless `set_quarantine_bytes` says
+/// otherwise.
+pub const DEFAULT_QUARANTINE_BYTES: usize = MB;
+// the quarantine is a fixed ring, so it's bounded by count as well
+const QUARANTINE_OBJECTS: usize = 1024;
+
+static QUARANTINE_BYTES: AtomicUsize = AtomicUsize::new(DEFAULT_QUARANTINE_BYTES);
+
+/// Set how many freed bytes each thread holds back; 0 sends objects straight
+/// back to their blocks, still poisoned.
+pub fn set_quarantine_bytes(bytes: usize) { QUARANTINE_BYTES.store(bytes, Ordering::Relaxed) }
+
+struct Quarantine {
+    objects: [(*mut u8, usize); QUARANTINE_OBJECTS],
+    // the oldest object is at head, and there are len of them
+    head: usize,
+    len: usize,
+    bytes: usize,
+}
+
+impl Quarantine {
+    fn pop(&mut self) -> *mut u8 {
+        let (object, size) = self.objects[self.head];
+        self.head = (self.head + 1) % QUARANTINE_OBJECTS;
+        self.len -= 1;
+        self.bytes -= size;
+        object
+    }
+}
+
+thread_local! {
+    static QUARANTINE: UnsafeCell<Quarantine> = UnsafeCell::new(Quarantine {
+        objects: [(ptr::null_mut(), 0); QUARANTINE_OBJECTS],
+        head: 0,
+        len: 0,
+        bytes: 0,
+    });
+}
+
+/// Fill the `size` bytes at `object` with poison.
+pub fn poison(object: *mut u8, size: usize) { unsafe { ptr::write_bytes(object, POISON, size) } }
+
+/// Poison a freed object of `size` bytes and hold it back, handing the oldest
+/// held objects to `release` for as long as there are too many bytes held.
+pub fn hold(object: *mut u8, size: usize, release: impl Fn(*mut u8)) {
+    poison(object, size);
+    let limit = QUARANTINE_BYTES.load(Ordering::Relaxed);
+    let full = with_quarantine(|quarantine| {
+        let oldest = match quarantine.len {
+            QUARANTINE_OBJECTS => quarantine.pop(),
+            _ => ptr::null_mut(),
+        };
+        let tail = (quarantine.head + quarantine.len) % QUARANTINE_OBJECTS;
+        quarantine.objects[tail] = (object, size);
+        quarantine.len += 1;
+        quarantine.bytes += size;
+        oldest
+    });
+    if !full.is_null() {
+        release(full);
+    }
+    release_while(release, |quarantine| quarantine.bytes > limit);
+}
+
+/// Hand everything the calling thread holds to `release`, e.g. as it exits.
+pub fn flush(release: impl Fn(*mut u8)) { release_while(release, |quarantine| quarantine.len > 0) }
+
+fn with_quarantine<R>(f: impl FnOnce(&mut Quarantine) -> R) -> R {
+    QUARANTINE.with(|quarantine| f(unsafe { &mut *quarantine.get() }))
+}
+
+// one at a time, and outside the borrow: a release may free into here again
+fn release_while(release: impl Fn(*mut u8), more: impl Fn(&Quarantine) -> bool) {
+    loop {
+        let object = with_quarantine(|quarantine| match more(quarantine) {
+            true => quarantine.pop(),
+            false => ptr::null_mut(),
+        });
+        if object.is_null() {
+            return
+        }
+        release(object);
+    }
+}
+
+/// Check that an object `block` is handing out again still holds the poison
+/// it was freed with; if not, it was written to while free, so report where
+/// and abort.
+pub fn check(object: *mut u8, block: &BlockHeader) {
+    let size = block._object_size();
+    let word = std::mem::size_of::<usize>();
+    if size <= word {
+        return
+    }
+    let body = unsafe { std::slice::from_raw_parts(object.add(word), size - word) };
+    if let Some(offset) = body.iter().position(|&byte| byte != POISON) {
+        write_after_free(object, word + offset, block);
+    }
+}
+
+#[cold]
+fn write_after_free(object: *mut u8, offset: usize, block: &BlockHeader) -> ! {
+    eprintln!(
+        "aura: write after free to {:#?} (+{}) in size class {} of block {:#?}",
+        object,
+        offset,
+        block._object_size(),
+        block as *const BlockHeader
+    );
+    std::process::abort()
+}
@@ -0,0 +1,246 @@
+//! Background scavenger: a thread that meshes fragmented size classes across
+//! every thread's blocks and gives empty blocks' pages back to the OS, so that
+//! none of it happens on the allocation paths.
+
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+use std::{mem, ptr};
+
+use parking_lot::{Condvar, Mutex};
+
+use super::block::{self, BlockHeader};
+use super::bucket::BUCKETS;
+use super::mesh::{self, MeshStats, MAX_MESH_CANDIDATES, MESHING_SUPPORTED, SPLIT_MESHER_PROBES};
+use super::segment::{self, SegmentList};
+use super::top_level;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ScavengerConfig {
+    /// Time between passes, at least.
+    pub interval: Duration,
+    /// Most meshes per second, averaged over a second.
+    pub meshes_per_sec: usize,
+    /// Share of one core the scavenger may keep busy, in (0, 1]. Passes are
+    /// spaced out further when they take long.
+    pub cpu_budget: f64,
+}
+
+impl Default for ScavengerConfig {
+    fn default() -> Self {
+        ScavengerConfig {
+            interval: Duration::from_millis(100),
+            meshes_per_sec: 1000,
+            cpu_budget: 0.05,
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct ScavengerStats {
+    pub passes: usize,
+    pub mesh: MeshStats,
+    /// Empty blocks whose pages were given back to the OS.
+    pub released: usize,
+}
+
+struct State {
+    config: ScavengerConfig,
+    paused: bool,
+    stop: bool,
+    stats: ScavengerStats,
+}
+
+struct Scavenger {
+    state: Mutex<State>,
+    wake: Condvar,
+    thread: Mutex<Option<JoinHandle<()>>>,
+}
+
+lazy_static! {
+    static ref SCAVENGER: Scavenger = Scavenger {
+        state: Mutex::new(State {
+            config: ScavengerConfig::default(),
+            paused: false,
+            stop: false,
+            stats: ScavengerStats::default(),
+        }),
+        wake: Condvar::new(),
+        thread: Mutex::new(None),
+    };
+}
+
+/// Start the scavenger thread. Returns false if it's already running.
+pub fn start(config: ScavengerConfig) -> bool {
+    assert!(config.cpu_budget > 0.0 && config.cpu_budget <= 1.0, "cpu_budget must be in (0, 1]");
+    let mut thread = SCAVENGER.thread.lock();
+    if thread.is_some() {
+        return false
+    }
+    {
+        let mut state = SCAVENGER.state.lock();
+        state.config = config;
+        state.paused = false;
+        state.stop = false;
+    }
+    *thread = Some(
+        thread::Builder::new()
+            .name("aura-scavenger".into())
+            .spawn(|| run(&SCAVENGER))
+            .expect("couldn't spawn the scavenger thread"),
+    );
+    true
+}
+
+/// Stop the scavenger thread and wait for it to exit.
+pub fn stop() {
+    let mut thread = SCAVENGER.thread.lock();
+    if let Some(handle) = thread.take() {
+        SCAVENGER.state.lock().stop = true;
+        SCAVENGER.wake.notify_all();
+        handle.join().expect("the scavenger thread panicked");
+    }
+}
+
+/// Pause or resume the scavenger; a pass that's underway runs to the end.
+pub fn set_paused(paused: bool) {
+    SCAVENGER.state.lock().paused = paused;
+    SCAVENGER.wake.notify_all();
+}
+
+/// Change the configuration of a running (or the next) scavenger.
+pub fn configure(config: ScavengerConfig) {
+    assert!(config.cpu_budget > 0.0 && config.cpu_budget <= 1.0, "cpu_budget must be in (0, 1]");
+    SCAVENGER.state.lock().config = config;
+    SCAVENGER.wake.notify_all();
+}
+
+/// Totals over every pass so far.
+pub fn stats() -> ScavengerStats { SCAVENGER.state.lock().stats }
+
+fn run(scavenger: &Scavenger) {
+    let mut allowance = 0f64;
+    let mut last_refill = Instant::now();
+    loop {
+        let config = {
+            let mut state = scavenger.state.lock();
+            while state.paused && !state.stop {
+                scavenger.wake.wait(&mut state);
+            }
+            if state.stop {
+                return
+            }
+            state.config
+        };
+
+        // token bucket: at most a second's worth of meshes saved up
+        let now = Instant::now();
+        let rate = config.meshes_per_sec as f64;
+        allowance = (allowance + rate * (now - last_refill).as_secs_f64()).min(rate);
+        last_refill = now;
+
+        let busy_since = thread_cpu_time();
+        let mesh = mesh_pass(allowance as usize);
+        allowance -= mesh.meshed as f64;
+        let released = top_level::get().purge(top_level::purge_delay());
+        let busy = thread_cpu_time().saturating_sub(busy_since);
+
+        // busy / (busy + idle) <= cpu_budget
+        let idle = config.interval.max(busy.mul_f64((1.0 - config.cpu_budget) / config.cpu_budget));
+        let mut state = scavenger.state.lock();
+        state.stats.passes += 1;
+        state.stats.mesh += mesh;
+        state.stats.released += released;
+        if !state.stop {
+            scavenger.wake.wait_for(&mut state, idle);
+        }
+    }
+}
+
+fn thread_cpu_time() -> Duration {
+    let mut ts: libc::timespec = unsafe { mem::zeroed() };
+    unsafe { libc::clock_gettime(libc::CLOCK_THREAD_CPUTIME_ID, &mut ts) };
+    Duration::new(ts.tv_sec as u64, ts.tv_nsec as u32)
+}
+
+/// One pass over the segment registry: mesh the inactive blocks of every size
+/// class with at least two sparse ones, up to `max_meshes` meshes in all.
+/// Blocks meshed away are handed back to their buckets for unlinking.
+pub fn mesh_pass(max_meshes: usize) -> MeshStats {
+    let mut stats = MeshStats::default();
+    if !MESHING_SUPPORTED || 0 == max_meshes {
+        return stats
+    }
+    let mut sparse = [0usize; BUCKETS];
+    for_each_block(&segment::registry().lock(), |block| {
+        if let Some(class) = candidate_class(block) {
+            sparse[class] += 1;
+        }
+    });
+
+    let mut candidates = [ptr::null_mut::<BlockHeader>(); MAX_MESH_CANDIDATES];
+    let mut pairs = [(ptr::null_mut::<BlockHeader>(), ptr::null_mut()); MAX_MESH_CANDIDATES / 2];
+    for class in (0..BUCKETS).filter(|&class| sparse[class] >= 2) {
+        // held until the class is done, so that no candidate's segment is
+        // released under it
+        let registry = segment::registry().lock();
+        let mut len = 0;
+        for_each_block(&registry, |block| {
+            if len < MAX_MESH_CANDIDATES && candidate_class(block) == Some(class) {
+                candidates[len] = block;
+                len += 1;
+            }
+        });
+        stats.candidates += len;
+
+        let found = block::THREAD_RNG.with(|rng| {
+            mesh::split_mesher(
+                &mut candidates[..len],
+                &mut pairs,
+                &mut *rng.borrow_mut(),
+                SPLIT_MESHER_PROBES,
+                |a, b| unsafe { &*a }.meshes_with(unsafe { &*b }),
+            )
+        });
+        stats.pairs += found;
+        for &(a, b) in &pairs[..found] {
+            if stats.meshed == max_meshes {
+                return stats
+            }
+            let (a_ref, b_ref) = unsafe { (&mut *a, &mut *b) };
+            let src = if a_ref.mesh_into(b_ref) {
+                a_ref
+            } else if b_ref.mesh_into(a_ref) {
+                b_ref
+            } else {
+                continue
+            };
+            // a meshed-away block keeps its bucket until the bucket unlinks it;
+            // if the bucket's thread exited just now, the block's been let go
+            // and goes to the top-level when it's unmeshed
+            let bucket = src._bucket();
+            if !bucket.is_null() {
+                unsafe { &*bucket }.meshed_away(src);
+            }
+            stats.meshed += 1;
+        }
+    }
+    stats
+}
+
+fn for_each_block(registry: &SegmentList, mut f: impl FnMut(&'static mut BlockHeader)) {
+    for segment in registry.iter() {
+        for i in 0..segment.num_blocks() {
+            f(unsafe { &mut *segment.block_header(i).get() });
+        }
+    }
+}
+
+/// Size class of a block that's sitting inactive in some thread's bucket and
+/// is sparse enough to mesh.
+fn candidate_class(block: &mut BlockHeader) -> Option<usize> {
+    let bucket = block._bucket();
+    if bucket.is_null() || !block.is_mesh_candidate(bucket) {
+        return None
+    }
+    Some(block.bucket_index())
+}
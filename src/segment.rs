@@ -1,6 +1,6 @@
 use std::cell::UnsafeCell;
 use std::pin::Pin;
-use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use std::{mem, ptr};
 
 use parking_lot::Mutex;
@@ -8,8 +8,9 @@ use parking_lot::Mutex;
 use super::block::BlockHeader;
 use super::bucket::*;
 use super::constants::{KB, MB};
+use super::segment_map;
 use super::util::extrinsic_bsr;
-use super::vm::{VMRegion, VirtualRegion};
+use super::vm::{self, VMRegion, VirtualRegion};
 
 #[derive(Clone, Copy)]
 #[repr(u8)]
@@ -36,9 +37,17 @@ pub struct SegmentHeader {
     // line 0
     block_shift: usize,
     kind: SegmentType,
-    padding0_0: [u8; 7],
+    // the last page of each block is a guard page
+    guarded: bool,
+    padding0_0: [u8; 6],
     size: usize,
-    padding0: [u64; 5],
+    // SegmentList
+    next_segment: *mut SegmentHeader,
+    // blocks not sitting in the top-level's empties; only changes under a
+    // lock on those, and only to or from 0 with nothing else taking blocks
+    // out of them
+    live_blocks: AtomicUsize,
+    padding0: [u64; 3],
 }
 
 #[repr(C)]
@@ -47,9 +56,91 @@ struct OpaqueExtendedSegmentHeader {
     block_headers: [UnsafeCell<BlockHeader>],
 }
 
+fn protect(start: usize, len: usize) {
+    let mut region = unsafe { VMRegion::from_raw_parts(start as *mut u8, len) };
+    if let Err(e) = region.prot(false, false) {
+        panic!("couldn't protect guard pages at {:#x}: {}", start, e);
+    }
+    region.consume();
+}
+
+// with `hardened`, for segments made from now on
+static BLOCK_GUARDS: AtomicBool = AtomicBool::new(false);
+
+/// With the `hardened` feature, put a guard page at the end of every block of
+/// the small segments made from now on, at the cost of that page. Off by
+/// default.
+pub fn set_block_guards(enabled: bool) { BLOCK_GUARDS.store(enabled, Ordering::Relaxed) }
+
+/// Intrusive list of segments, linked through their headers so that
+/// registering a segment never allocates.
+pub struct SegmentList {
+    head: *mut SegmentHeader,
+    len: usize,
+}
+
+impl SegmentList {
+    pub const fn new() -> SegmentList { SegmentList { head: ptr::null_mut(), len: 0 } }
+
+    pub fn len(&self) -> usize { self.len }
+
+    pub fn push(&mut self, segment: &'static mut SegmentHeader) {
+        segment.next_segment = self.head;
+        self.head = segment as *mut SegmentHeader;
+        self.len += 1;
+    }
+
+    /// Unlink a particular segment, if it's in the list.
+    pub fn remove(&mut self, segment: *const SegmentHeader) -> bool {
+        let mut link: *mut *mut SegmentHeader = &mut self.head;
+        while !unsafe { *link }.is_null() {
+            let curr = unsafe { *link };
+            if curr as *const SegmentHeader == segment {
+                let curr_ref = unsafe { &mut *curr };
+                unsafe { *link = curr_ref.next_segment };
+                curr_ref.next_segment = ptr::null_mut();
+                self.len -= 1;
+                return true
+            }
+            link = unsafe { &mut (*curr).next_segment };
+        }
+        false
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &'static SegmentHeader> {
+        let mut curr = self.head;
+        std::iter::from_fn(move || {
+            if curr.is_null() {
+                return None
+            }
+            let segment = unsafe { &*curr };
+            curr = segment.next_segment;
+            Some(segment)
+        })
+    }
+}
+
+unsafe impl Send for SegmentList {}
+
+// Lowest and highest address covered by any segment; lets frees of pointers
+// that were never ours (e.g. from before the C shim took over) be told apart
+// cheaply. On Linux segments live in the few arenas reserved for them, so
+// little else falls in between.
+static SEGMENT_LOW: AtomicUsize = AtomicUsize::new(usize::MAX);
+static SEGMENT_HIGH: AtomicUsize = AtomicUsize::new(0);
+
+pub fn may_contain(ptr: *const u8) -> bool {
+    let addr = ptr as usize;
+    addr >= SEGMENT_LOW.load(Ordering::Acquire) && addr < SEGMENT_HIGH.load(Ordering::Acquire)
+}
+
+fn note_bounds(vm_region: &VMRegion) {
+    SEGMENT_LOW.fetch_min(vm_region.base() as usize, Ordering::AcqRel);
+    SEGMENT_HIGH.fetch_max(vm_region.base() as usize + vm_region.size(), Ordering::AcqRel);
+}
+
 lazy_static! {
-    static ref SEGMENT_REGISTRY: Arc<Mutex<Vec<&'static SegmentHeader>>> =
-        Arc::new(Mutex::new(Vec::new()));
+    static ref SEGMENT_REGISTRY: Mutex<SegmentList> = Mutex::new(SegmentList::new());
 }
 
 // static mut SEGMENT_REGISTRY: Option<Arc<Mutex<Vec<&'static SegmentHeader>>>>
@@ -57,13 +148,13 @@ lazy_static! {
 
 // pub fn init_registry() { unsafe { SEGMENT_REGISTRY =
 // Some(Arc::new(Mutex::new(Vec::new()))) }; }
-pub fn registry() -> Arc<Mutex<Vec<&'static SegmentHeader>>> {
+pub fn registry() -> &'static Mutex<SegmentList> {
     // unsafe { SEGMENT_REGISTRY.as_ref().unwrap_unchecked().clone() }
-    SEGMENT_REGISTRY.clone()
+    &SEGMENT_REGISTRY
 }
 
 impl SegmentHeader {
-    pub fn new(kind: SegmentType) -> Option<Vec<&'static UnsafeCell<BlockHeader>>> {
+    pub fn new(kind: SegmentType) -> Option<&'static SegmentHeader> {
         debug_assert!(match kind {
             SegmentType::Small | SegmentType::Large => true,
             _ => false,
@@ -78,12 +169,18 @@ impl SegmentHeader {
             ptr::write(vm_region.base() as *mut SegmentHeader, SegmentHeader {
                 block_shift: match kind {
                     SegmentType::Small => const { extrinsic_bsr(64 * KB - 1) },
-                    SegmentType::Large => const { extrinsic_bsr(4 * MB - 1) },
+                    SegmentType::Large => const { extrinsic_bsr(512 * KB - 1) },
                     SegmentType::Huge => unreachable!(),
                 },
                 kind,
+                // the largest size classes of large blocks fill them completely
+                guarded: cfg!(feature = "hardened")
+                    && matches!(kind, SegmentType::Small)
+                    && BLOCK_GUARDS.load(Ordering::Relaxed),
                 padding0_0: Default::default(),
                 size: vm_region.size(),
+                next_segment: ptr::null_mut(),
+                live_blocks: AtomicUsize::new(Self::num_blocks_for(kind)),
                 padding0: Default::default(),
             });
         }
@@ -104,11 +201,8 @@ impl SegmentHeader {
             // let block_body_offset = mem::size_of::<SegmentHeader>()
             //     + mem::size_of::<UnsafeCell<BlockHeader>>() * num_block_headers
             //     + i * block_size;
-            let block_body_offset = match kind {
-                SegmentType::Small => (i + 1) * block_size,
-                SegmentType::Large => unimplemented!(),
-                SegmentType::Huge => unimplemented!(),
-            };
+            // the first block's worth of the segment holds the headers
+            let block_body_offset = (i + 1) * block_size;
             let block_body_ptr = unsafe { vm_region.base().offset(block_body_offset as isize) };
             unsafe {
                 ptr::write(
@@ -118,24 +212,174 @@ impl SegmentHeader {
             }
         }
 
+        if cfg!(feature = "hardened") {
+            header.protect_guards();
+        }
+        note_bounds(&vm_region);
+
         // update registry
+        segment_map::insert(vm_region.base() as usize);
         let registry = registry();
         registry.lock().push(header);
 
-        Some({
-            (0..num_block_headers)
-                .map(|idx| unsafe { header.block_header(idx) })
-                .collect::<Vec<_>>()
-        })
+        Some(unsafe { &*(vm_region.base() as *const SegmentHeader) })
     }
 
+    /// Segment holding a single object, for objects no size class can serve
+    /// (too big, or too strictly aligned). The object starts at the first
+    /// `align`-aligned page after the headers, which keeps it inside the first
+    /// 4 MB of the segment; hence `align` can be at most 2 MB.
+    ///
+    /// The segment takes whole pages: the alignment is all in where the object
+    /// starts, so past it the segment only has to round up to the page.
+    /// Dedicated segments aren't registered: they never hold free blocks.
+    pub fn new_dedicated(size: usize, align: usize) -> Option<*mut u8> {
+        debug_assert!(align.is_power_of_two() && align <= 2 * MB);
+        let body_offset = align.max(vm::page_size());
+        debug_assert!(
+            mem::size_of::<SegmentHeader>() + mem::size_of::<UnsafeCell<BlockHeader>>()
+                <= body_offset
+        );
+        let total = Self::dedicated_size(body_offset, size)?;
+        let vm_region = VMRegion::new(total, 4 * MB).ok()?;
+        Some(unsafe { Self::format_dedicated(&vm_region, body_offset) })
+    }
+
+    fn dedicated_size(body_offset: usize, size: usize) -> Option<usize> {
+        let page = vm::page_size();
+        Some(body_offset.checked_add(size)?.checked_add(page - 1)? & !(page - 1))
+    }
+
+    /// Write the headers of a dedicated segment over `vm_region`, whose object
+    /// starts at `body_offset`, and enter it in the segment map. Returns the
+    /// object.
+    unsafe fn format_dedicated(vm_region: &VMRegion, body_offset: usize) -> *mut u8 {
+        ptr::write(vm_region.base() as *mut SegmentHeader, SegmentHeader {
+            // so that the object lands in block 0 (see find_block_for_object)
+            block_shift: extrinsic_bsr(body_offset - 1),
+            kind: SegmentType::Huge,
+            guarded: false,
+            padding0_0: Default::default(),
+            size: vm_region.size(),
+            next_segment: ptr::null_mut(),
+            live_blocks: AtomicUsize::new(1),
+            padding0: Default::default(),
+        });
+        let header: &'static SegmentHeader = mem::transmute::<_, _>(vm_region.base());
+        let body = vm_region.base().add(body_offset);
+        let block_header_ptr =
+            mem::transmute::<_, *mut UnsafeCell<BlockHeader>>(header.block_header(0));
+        ptr::write(block_header_ptr, UnsafeCell::new(BlockHeader::from_raw_parts(body, 0)));
+        (*(*block_header_ptr).get()).format_dedicated(vm_region.size() - body_offset);
+        note_bounds(vm_region);
+        segment_map::insert(vm_region.base() as usize);
+
+        body
+    }
+
+    /// Resize the object of a dedicated segment through the VM layer, without
+    /// copying it: shrinking gives the pages past its new end back, and growing
+    /// takes the address space right after the segment if that's free, or
+    /// else moves the segment's pages to where there's room. Returns the
+    /// object, which only moves in that last case, or None if the VM layer
+    /// can't do it.
+    pub unsafe fn resize_dedicated(object: *mut u8, size: usize) -> Option<*mut u8> {
+        let base = (object as usize & !(4 * MB - 1)) as *mut u8;
+        let header = &*(base as *const SegmentHeader);
+        debug_assert!(matches!(header.kind, SegmentType::Huge));
+        let body_offset = header.block_size();
+        let total = Self::dedicated_size(body_offset, size)?;
+        let mut vm_region = VMRegion::from_raw_parts(base, header.size);
+        if total == header.size {
+            return Some(object)
+        } else if total < header.size {
+            vm_region.shrink(total).ok()?;
+        } else if vm_region.extend(total).is_err() {
+            segment_map::remove(base as usize);
+            if vm_region.remap(total, 4 * MB).is_err() {
+                segment_map::insert(base as usize);
+                return None
+            }
+        }
+        Some(Self::format_dedicated(&vm_region, body_offset))
+    }
+
+    /// Make the pages between the block headers and the first block's body
+    /// inaccessible, and the last page of every block if the segment is
+    /// guarded, so that running off the end of a block or into the headers
+    /// faults instead of corrupting them.
+    fn protect_guards(&self) {
+        let base = self as *const SegmentHeader as usize;
+        let page = vm::page_size();
+        let headers_end = unsafe { self.block_header(self.num_blocks() - 1) }.get() as usize
+            + mem::size_of::<UnsafeCell<BlockHeader>>();
+        let guard = (headers_end + page - 1) & !(page - 1);
+        debug_assert!(guard < base + self.block_size(), "no room for a guard after the headers");
+        protect(guard, base + self.block_size() - guard);
+        if self.guarded {
+            for body in (1..=self.num_blocks()).map(|i| base + i * self.block_size()) {
+                protect(body + self.block_span(), page);
+            }
+        }
+    }
+
+    /// Release a segment made by `new_dedicated`, along with its object.
+    pub unsafe fn free_dedicated(&self) {
+        debug_assert!(matches!(self.kind, SegmentType::Huge));
+        segment_map::remove(self as *const SegmentHeader as usize);
+        let region = VMRegion::from_raw_parts(self as *const SegmentHeader as *mut u8, self.size);
+        if let Err(e) = region.free() {
+            panic!("couldn't release dedicated segment {:#?}: {}", self as *const _, e);
+        }
+    }
+
+    /// Unregister a segment none of whose blocks are in use any more, and
+    /// unmap it. Its block headers must be out of every list already.
+    pub unsafe fn release(&self) {
+        debug_assert!(0 == self.live_blocks.load(Ordering::Relaxed));
+        // once it's out of the registry, the scavenger can't be looking at it
+        registry().lock().remove(self);
+        segment_map::remove(self as *const SegmentHeader as usize);
+        let region = VMRegion::from_raw_parts(self as *const SegmentHeader as *mut u8, self.size);
+        if let Err(e) = region.free() {
+            panic!("couldn't release segment {:#?}: {}", self as *const _, e);
+        }
+    }
+
+    /// Note that one of this segment's blocks went to the top-level's empties.
+    /// Returns true if that leaves no block of the segment in use.
+    pub fn block_emptied(&self) -> bool { 1 == self.live_blocks.fetch_sub(1, Ordering::Relaxed) }
+
+    /// Like `block_emptied`, unless that would leave no block of the segment
+    /// in use; returns false without noting anything then.
+    pub fn block_emptied_unless_last(&self) -> bool {
+        self.live_blocks
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |live| (live > 1).then(|| live - 1))
+            .is_ok()
+    }
+
+    /// Note that one of this segment's empty blocks was taken for use. Returns
+    /// true if no block of the segment was in use before.
+    pub fn block_reused(&self) -> bool { 0 == self.live_blocks.fetch_add(1, Ordering::Relaxed) }
+
+    pub fn kind(&self) -> SegmentType { self.kind }
     pub fn block_shift(&self) -> usize { self.block_shift }
     pub fn block_size(&self) -> usize { 1 << self.block_shift }
+    /// How much of each block's body objects can go in: all of it, unless the
+    /// segment has guard pages between its blocks.
+    pub fn block_span(&self) -> usize {
+        if self.guarded {
+            self.block_size() - vm::page_size()
+        } else {
+            self.block_size()
+        }
+    }
     pub fn num_blocks(&self) -> usize { Self::num_blocks_for(self.kind) }
     pub const fn num_blocks_for(kind: SegmentType) -> usize {
         match kind {
             SegmentType::Small => 63,
-            SegmentType::Large => 1,
+            // 512 KB blocks, so that the largest size class still fits
+            SegmentType::Large => 7,
             SegmentType::Huge => 1,
         }
     }
@@ -0,0 +1,84 @@
+//! Which 4 MB-aligned addresses are the bases of live segments, registered or
+//! dedicated: one bit per possible segment over the whole user address space,
+//! so that a pointer can be checked for being aura's without taking the
+//! registry lock. The bitmap is mapped on first use and only the pages of it
+//! that cover segments ever get touched.
+
+use std::ptr;
+use std::sync::atomic::{AtomicPtr, AtomicU64, Ordering};
+
+use crate::constants::MB;
+
+const SEGMENT_SHIFT: usize = 22;
+// no user address space above 48 bits, on any platform we run on
+const ADDRESS_BITS: usize = 48;
+const WORDS: usize = 1 << (ADDRESS_BITS - SEGMENT_SHIFT - 6);
+
+static MAP: AtomicPtr<AtomicU64> = AtomicPtr::new(ptr::null_mut());
+
+fn map() -> &'static [AtomicU64] {
+    let mut map = MAP.load(Ordering::Acquire);
+    if map.is_null() {
+        let size = WORDS * 8;
+        let addr = unsafe {
+            libc::mmap(
+                ptr::null_mut(),
+                size,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_PRIVATE | libc::MAP_ANONYMOUS | libc::MAP_NORESERVE,
+                -1,
+                0,
+            )
+        };
+        if addr == libc::MAP_FAILED {
+            panic!("couldn't map the segment map");
+        }
+        map = match MAP.compare_exchange(
+            ptr::null_mut(),
+            addr as *mut AtomicU64,
+            Ordering::AcqRel,
+            Ordering::Acquire,
+        ) {
+            Ok(_) => addr as *mut AtomicU64,
+            Err(winner) => {
+                unsafe { libc::munmap(addr, size) };
+                winner
+            },
+        };
+    }
+    unsafe { std::slice::from_raw_parts(map, WORDS) }
+}
+
+fn position(base: usize) -> Option<(usize, u64)> {
+    debug_assert!(0 == base & (4 * MB - 1));
+    let index = base >> SEGMENT_SHIFT;
+    if index < WORDS * 64 {
+        Some((index / 64, 1u64 << (index % 64)))
+    } else {
+        None
+    }
+}
+
+/// Note that a segment starts at `base`.
+pub fn insert(base: usize) {
+    let (word, bit) = position(base).expect("segment beyond the segment map");
+    map()[word].fetch_or(bit, Ordering::Release);
+}
+
+/// Note that the segment at `base` is going away. Returns false if it wasn't
+/// there to begin with.
+pub fn remove(base: usize) -> bool {
+    let (word, bit) = position(base).expect("segment beyond the segment map");
+    0 != map()[word].fetch_and(!bit, Ordering::AcqRel) & bit
+}
+
+/// Whether a live segment starts at `base`.
+pub fn contains(base: usize) -> bool {
+    let map = MAP.load(Ordering::Acquire);
+    match position(base) {
+        Some((word, bit)) if !map.is_null() => {
+            0 != unsafe { &*map.add(word) }.load(Ordering::Acquire) & bit
+        },
+        _ => false,
+    }
+}
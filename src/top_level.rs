@@ -1,21 +1,288 @@
 use std::cell::UnsafeCell;
 use std::default::Default;
-use std::mem::{self, MaybeUninit};
+use std::mem;
 use std::ptr;
-use std::sync::atomic::{AtomicUsize, Ordering};
-use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::time::Duration;
 
 use parking_lot::Mutex;
 
 use super::block::{self, BlockHeader};
 use super::bucket::*;
+use super::heap;
 use super::segment::{SegmentHeader, SegmentType};
+use crate::util::monotonic_ns;
+
+/// How long a block stays empty before its pages go back to the OS.
+pub const DEFAULT_PURGE_DELAY: Duration = Duration::from_millis(10);
+
+// in ns
+static PURGE_DELAY: AtomicU64 = AtomicU64::new(DEFAULT_PURGE_DELAY.as_nanos() as u64);
+// empties aren't checked for purging more than once per delay
+static NEXT_PURGE: AtomicU64 = AtomicU64::new(0);
+
+pub fn set_purge_delay(delay: Duration) {
+    PURGE_DELAY.store(delay.as_nanos().min(u64::MAX as u128) as u64, Ordering::Relaxed);
+    // don't wait out a check scheduled under the old delay
+    NEXT_PURGE.store(0, Ordering::Relaxed);
+}
+
+pub fn purge_delay() -> u64 { PURGE_DELAY.load(Ordering::Relaxed) }
+
+/// How many segments with no blocks in use are kept mapped, per segment type,
+/// rather than given back to the OS.
+pub const DEFAULT_SEGMENT_CACHE: usize = 1;
+
+static SEGMENT_CACHE: AtomicUsize = AtomicUsize::new(DEFAULT_SEGMENT_CACHE);
+
+/// Segments already cached beyond the new limit stay until they're used again.
+pub fn set_segment_cache(segments: usize) { SEGMENT_CACHE.store(segments, Ordering::Relaxed) }
+
+/// Intrusive list of block headers, linked through `next_in_bucket` (a block
+/// held by the top-level is never in a bucket). Doesn't allocate, so it's safe
+/// to use underneath the global allocator.
+pub struct BlockList {
+    head: *mut BlockHeader,
+    len: usize,
+}
+
+impl BlockList {
+    pub const fn new() -> BlockList { BlockList { head: ptr::null_mut(), len: 0 } }
+
+    pub fn len(&self) -> usize { self.len }
+    pub fn is_empty(&self) -> bool { self.head.is_null() }
+
+    pub fn push(&mut self, header: &'static UnsafeCell<BlockHeader>) {
+        unsafe { &mut *header.get() }.next_in_bucket = self.head;
+        self.head = header.get();
+        self.len += 1;
+    }
+
+    pub fn pop(&mut self) -> Option<&'static UnsafeCell<BlockHeader>> {
+        if self.head.is_null() {
+            return None
+        }
+        let header = self.head;
+        let header_ref = unsafe { &mut *header };
+        self.head = header_ref.next_in_bucket;
+        header_ref.next_in_bucket = ptr::null_mut();
+        self.len -= 1;
+        Some(unsafe {
+            mem::transmute::<*mut BlockHeader, &'static UnsafeCell<BlockHeader>>(header)
+        })
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = *mut BlockHeader> + '_ {
+        let mut curr = self.head;
+        std::iter::from_fn(move || {
+            if curr.is_null() {
+                return None
+            }
+            let header = curr;
+            curr = unsafe { &*curr }.next_in_bucket;
+            Some(header)
+        })
+    }
+
+    /// Unlink a particular block header, if it's in the list.
+    pub fn remove(&mut self, header: *mut BlockHeader) -> Option<&'static UnsafeCell<BlockHeader>> {
+        let mut link: *mut *mut BlockHeader = &mut self.head;
+        while !unsafe { *link }.is_null() {
+            let curr = unsafe { *link };
+            if curr == header {
+                let curr_ref = unsafe { &mut *curr };
+                unsafe { *link = curr_ref.next_in_bucket };
+                curr_ref.next_in_bucket = ptr::null_mut();
+                self.len -= 1;
+                return Some(unsafe {
+                    mem::transmute::<*mut BlockHeader, &'static UnsafeCell<BlockHeader>>(curr)
+                })
+            }
+            link = unsafe { &mut (*curr).next_in_bucket };
+        }
+        None
+    }
+
+    /// Unlink every block header of a segment. Returns how many there were.
+    pub fn remove_segment(&mut self, segment: &SegmentHeader) -> usize {
+        let mut removed = 0;
+        let mut link: *mut *mut BlockHeader = &mut self.head;
+        while !unsafe { *link }.is_null() {
+            let curr_ref = unsafe { &mut **link };
+            if ptr::eq(curr_ref.get_segment(), segment) {
+                unsafe { *link = curr_ref.next_in_bucket };
+                curr_ref.next_in_bucket = ptr::null_mut();
+                removed += 1;
+            } else {
+                link = &mut curr_ref.next_in_bucket;
+            }
+        }
+        self.len -= removed;
+        removed
+    }
+}
+
+impl Default for BlockList {
+    fn default() -> Self { BlockList::new() }
+}
+
+unsafe impl Send for BlockList {}
+
+/// How many shards each of the top-level's lists is split into.
+pub const SHARDS: usize = 16;
+
+// how many of the shards blocks get pushed to; the rest are only drained
+static ACTIVE_SHARDS: AtomicUsize = AtomicUsize::new(SHARDS);
+
+/// Spread blocks handed to the top-level over `shards` shards (clamped to
+/// 1..=SHARDS); 1 makes every list a single locked list again.
+pub fn set_shards(shards: usize) { ACTIVE_SHARDS.store(shards.clamp(1, SHARDS), Ordering::Relaxed) }
+
+/// The CPU we're running on, or where that can't be had, something that
+/// at least stays the same for the thread.
+fn current_cpu() -> usize {
+    #[cfg(target_os = "linux")]
+    {
+        let cpu = unsafe { libc::sched_getcpu() };
+        if cpu >= 0 {
+            return cpu as usize
+        }
+    }
+    heap::thread_id().get() as usize
+}
+
+// a lock per cache line, so that neighbouring shards don't contend anyway
+#[repr(align(64))]
+struct Shard(Mutex<BlockList>);
+
+/// A block list split into shards with a lock each, so that threads on
+/// different CPUs handing blocks to and taking them from the top-level don't
+/// serialize on one lock. Blocks go to the shard of the CPU that pushes them;
+/// pops try that shard first and then steal from the others.
+///
+/// This isn't a lock-free stack: blocks get taken out of the middle of the
+/// lists (`TopLevel::free`, `adopt`, releasing a segment) and purging walks
+/// them, none of which a Treiber stack can do without a lock of its own.
+pub struct ShardedBlockList {
+    shards: [Shard; SHARDS],
+}
+
+impl ShardedBlockList {
+    #[allow(clippy::declare_interior_mutable_const)]
+    const EMPTY_SHARD: Shard = Shard(parking_lot::const_mutex(BlockList::new()));
+    #[allow(clippy::declare_interior_mutable_const)]
+    const EMPTY: ShardedBlockList = ShardedBlockList::new();
+
+    pub const fn new() -> ShardedBlockList {
+        ShardedBlockList { shards: [Self::EMPTY_SHARD; SHARDS] }
+    }
+
+    fn home() -> usize { current_cpu() % ACTIVE_SHARDS.load(Ordering::Relaxed) }
+
+    /// Shards in the order to look for a block in: ours, then the rest.
+    fn from_home(&self) -> impl Iterator<Item = &'_ Mutex<BlockList>> {
+        let home = Self::home();
+        (0..SHARDS).map(move |i| &self.shards[(home + i) % SHARDS].0)
+    }
+
+    pub fn len(&self) -> usize { self.shards.iter().map(|shard| shard.0.lock().len()).sum() }
+
+    /// Push onto our shard, and run `then` while still holding its lock.
+    pub fn push<R>(&self, header: &'static UnsafeCell<BlockHeader>, then: impl FnOnce() -> R) -> R {
+        let mut guard = self.shards[Self::home()].0.lock();
+        guard.push(header);
+        then()
+    }
+
+    /// Pop a block, and run `then` on it while still holding the lock on the
+    /// shard it came from.
+    pub fn pop(
+        &self,
+        then: impl FnOnce(&'static UnsafeCell<BlockHeader>),
+    ) -> Option<&'static UnsafeCell<BlockHeader>> {
+        for shard in self.from_home() {
+            let mut guard = shard.lock();
+            if let Some(header) = guard.pop() {
+                then(header);
+                return Some(header)
+            }
+        }
+        None
+    }
+
+    /// Unlink a particular block header, if it's in the list. The block mustn't
+    /// be able to move between shards meanwhile.
+    pub fn remove(&self, header: *mut BlockHeader) -> Option<&'static UnsafeCell<BlockHeader>> {
+        self.shards.iter().find_map(|shard| shard.0.lock().remove(header))
+    }
+
+    /// Unlink the first block header `pred` holds for, looking in our shard
+    /// first. `pred` runs under the lock on the shard it looks at.
+    pub fn take(&self, mut pred: impl FnMut(*mut BlockHeader) -> bool) -> Option<*mut BlockHeader> {
+        self.from_home().find_map(|shard| {
+            let mut guard = shard.lock();
+            let header = guard.iter().find(|&header| pred(header))?;
+            guard.remove(header);
+            Some(header)
+        })
+    }
+
+    /// Run `f` on every block header, one shard at a time.
+    pub fn for_each(&self, mut f: impl FnMut(*mut BlockHeader)) {
+        for shard in &self.shards {
+            shard.0.lock().iter().for_each(&mut f);
+        }
+    }
+
+    /// Lock every shard, in order, which shuts out every other use of the
+    /// list until the returned guard is dropped.
+    pub fn lock_all(&self) -> AllShardsGuard<'_> {
+        for shard in &self.shards {
+            mem::forget(shard.0.lock());
+        }
+        AllShardsGuard { list: self }
+    }
+}
+
+impl Default for ShardedBlockList {
+    fn default() -> Self { ShardedBlockList::new() }
+}
+
+pub struct AllShardsGuard<'a> {
+    list: &'a ShardedBlockList,
+}
+
+impl AllShardsGuard<'_> {
+    /// Unlink every block header of a segment. Returns how many there were.
+    pub fn remove_segment(&mut self, segment: &SegmentHeader) -> usize {
+        self.list
+            .shards
+            .iter()
+            .map(|shard| unsafe { &mut *shard.0.data_ptr() }.remove_segment(segment))
+            .sum()
+    }
+}
+
+impl Drop for AllShardsGuard<'_> {
+    fn drop(&mut self) {
+        for shard in self.list.shards.iter().rev() {
+            unsafe { shard.0.force_unlock() };
+        }
+    }
+}
 
 #[repr(C)]
 pub struct TopLevel {
-    empties: Mutex<Vec<&'static UnsafeCell<BlockHeader>>>,
-    buckets: [Mutex<Vec<&'static UnsafeCell<BlockHeader>>>; BUCKETS],
+    // kept apart by segment type: blocks differ in size between the two
+    small_empties: ShardedBlockList,
+    large_empties: ShardedBlockList,
+    buckets: [ShardedBlockList; BUCKETS],
     total_count: AtomicUsize,
+    // segments with every block in the empties, by segment type; each only
+    // goes up with every shard of the matching empties locked, and down under
+    // the lock on one of them
+    small_cached: AtomicUsize,
+    large_cached: AtomicUsize,
 }
 
 /// For use with TopLevel::count
@@ -33,31 +300,26 @@ impl TopLevel {
     // New empty toplevel
     pub fn new() -> TopLevel {
         TopLevel {
-            empties: Mutex::new(Vec::new()),
-            buckets: {
-                let mut data: [MaybeUninit<Mutex<Vec<&'static UnsafeCell<BlockHeader>>>>; BUCKETS] =
-                    unsafe { MaybeUninit::uninit().assume_init() };
-                for elem in &mut data[..] {
-                    unsafe { ptr::write(elem.as_mut_ptr(), Default::default()) };
-                }
-                unsafe { mem::transmute::<_, _>(data) }
-            },
+            small_empties: ShardedBlockList::new(),
+            large_empties: ShardedBlockList::new(),
+            buckets: [ShardedBlockList::EMPTY; BUCKETS],
             total_count: AtomicUsize::new(0),
+            small_cached: AtomicUsize::new(0),
+            large_cached: AtomicUsize::new(0),
         }
     }
 
     /// Number of block headers are there in a particular bucket.
     pub fn count(&self, block_type: TopLevelBlockType) -> usize {
-        let which = match block_type {
-            TopLevelBlockType::Empty => self.empties.lock(),
-            TopLevelBlockType::Total => return self.total_count.load(Ordering::Relaxed),
-            TopLevelBlockType::Bucket(bucket) => self.indexed(bucket).lock(),
-        };
-        which.len()
+        match block_type {
+            TopLevelBlockType::Empty => self.small_empties.len() + self.large_empties.len(),
+            TopLevelBlockType::Total => self.total_count.load(Ordering::Relaxed),
+            TopLevelBlockType::Bucket(bucket) => self.indexed(bucket).len(),
+        }
     }
 
-    /// Get reference to mutex around bucket with index.
-    pub fn indexed(&self, index: usize) -> &'_ Mutex<Vec<&'static UnsafeCell<BlockHeader>>> {
+    /// Get reference to the list of the bucket with index.
+    pub fn indexed(&self, index: usize) -> &'_ ShardedBlockList {
         if index < BUCKETS {
             unsafe { &self.buckets.get_unchecked(index) }
         } else {
@@ -65,48 +327,161 @@ impl TopLevel {
         }
     }
 
+    /// Empty blocks that can be formatted for a bucket of this segment type.
+    fn empties(&self, kind: SegmentType) -> &'_ ShardedBlockList {
+        match kind {
+            SegmentType::Small => &self.small_empties,
+            SegmentType::Large => &self.large_empties,
+            SegmentType::Huge => panic!("dedicated segments have no top-level blocks"),
+        }
+    }
+
+    fn cached(&self, kind: SegmentType) -> &'_ AtomicUsize {
+        match kind {
+            SegmentType::Small => &self.small_cached,
+            SegmentType::Large => &self.large_cached,
+            SegmentType::Huge => panic!("dedicated segments have no top-level blocks"),
+        }
+    }
+
     /// Free a block header that is already present in the top-level.
     pub fn free(&self, block_ref: &BlockHeader) {
-        let index = bucket_select(block_ref._object_size());
-        let mut bh_vec = unsafe { self.indexed_unchecked(index) }.lock();
-        let vec_idx = bh_vec
-            .iter()
-            .position(|&item| {
-                item.get() == unsafe { mem::transmute::<_, *mut BlockHeader>(block_ref) }
-            })
-            .unwrap();
-        let header = bh_vec.remove(vec_idx);
-        drop(bh_vec);
+        let index = block_ref.bucket_index();
+        let header = unsafe { self.indexed_unchecked(index) }
+            .remove(unsafe { mem::transmute::<_, *mut BlockHeader>(block_ref) });
+
+        match header {
+            Some(header) => {
+                // as in Bucket::source_block: keep frees off it until it's moved
+                block_ref.flags.fetch_xor(
+                    block::BLOCK_FLAGS_FREE_LOCK | block::BLOCK_FLAGS_MAYBE_FREE,
+                    Ordering::SeqCst,
+                );
+                self.receive(index, header)
+            },
+            // abandoned while already empty: it went straight to the empties
+            None => {
+                block_ref.flags.fetch_and(!block::BLOCK_FLAGS_MAYBE_FREE, Ordering::SeqCst);
+            },
+        }
+    }
 
-        self.receive(index, header);
+    /// Take in a block from the bucket of a thread that exited, with its free
+    /// lock held. A block that's in use, or that a free is on its way to hand
+    /// back (and will look for here), waits in the bucket's list for adoption.
+    pub fn receive_abandoned(&self, index: usize, header: &'static UnsafeCell<BlockHeader>) {
+        let b_ref = unsafe { &*header.get() };
+        let flags = b_ref.flags.load(Ordering::SeqCst);
+        if b_ref.allocated() == 0 && 0 == flags & block::BLOCK_FLAGS_MAYBE_FREE {
+            return self.receive(index, header)
+        }
+        self.indexed(index).push(header, || {
+            b_ref.flags.fetch_and(!block::BLOCK_FLAGS_FREE_LOCK, Ordering::SeqCst);
+        });
+    }
+
+    /// Adopt a block that an exited thread left in use, for `bucket`. Blocks a
+    /// free is about to hand back are passed over, and so are full ones: no
+    /// one allocates from an abandoned block, so only frees can make room.
+    pub fn adopt(&self, index: usize, bucket: *mut Bucket) -> Option<*mut BlockHeader> {
+        let adoptable = self.indexed(index).take(|header| {
+            let header = unsafe { &*header };
+            header.allocated() < header._count() && header.try_lock_free()
+        })?;
+        unsafe { &mut *adoptable }.prep_adopted(bucket);
+        Some(adoptable)
     }
 
-    /// Add a block header to the top-level.
+    /// Add a block header to the top-level. An empty block that leaves its
+    /// segment with no blocks in use gets the whole segment released, unless
+    /// the segment cache has room for it.
     pub fn receive(&self, index: usize, header: &'static UnsafeCell<BlockHeader>) {
         let b_ref = unsafe { mem::transmute::<*mut BlockHeader, &mut BlockHeader>(header.get()) };
-        let mut guard =
-            if b_ref.allocated() == 0 { self.empties.lock() } else { self.indexed(index).lock() };
-        guard.push(header);
-        b_ref.flags.fetch_and(!block::BLOCK_FLAGS_FREE_LOCK, Ordering::SeqCst);
+        // `free` looks blocks up by their own class
+        debug_assert_eq!(index, b_ref.bucket_index());
+        if b_ref.allocated() != 0 {
+            self.indexed(index).push(header, || {
+                b_ref.flags.fetch_and(!block::BLOCK_FLAGS_FREE_LOCK, Ordering::SeqCst);
+            });
+            return
+        }
+
+        // an empty block can't share its pages any more
+        b_ref.release_meshed();
+        b_ref.note_emptied(monotonic_ns());
+        let segment = b_ref.get_segment();
+        let kind = segment.kind();
+        let empties = self.empties(kind);
+        let last = !empties.push(header, || {
+            b_ref.flags.fetch_and(!block::BLOCK_FLAGS_FREE_LOCK, Ordering::SeqCst);
+            segment.block_emptied_unless_last()
+        });
+        // a segment only runs out of blocks in use with nothing able to take
+        // its blocks out of the empties, so that it can be released whole
+        let mut guard = last.then(|| empties.lock_all());
+        let release = guard.is_some() && segment.block_emptied() && {
+            let cached = self.cached(kind);
+            let cache = cached.load(Ordering::Relaxed) < SEGMENT_CACHE.load(Ordering::Relaxed);
+            if cache {
+                cached.fetch_add(1, Ordering::Relaxed);
+            }
+            !cache
+        };
+        if !release {
+            drop(guard);
+            self.maybe_purge();
+            return
+        }
+        let removed = guard.as_mut().map_or(0, |guard| guard.remove_segment(segment));
+        debug_assert_eq!(removed, segment.num_blocks());
+        drop(guard);
+        self.total_count.fetch_sub(removed, Ordering::Relaxed);
+        unsafe { segment.release() };
     }
 
-    /// Request a block from bucket specified by index, otherwise a block sized
-    /// appropriately to that bucket, if one can be got, otherwise (finally)
-    /// None.
-    pub fn request(&self, index: usize) -> Option<&'static UnsafeCell<BlockHeader>> {
-        // Try to find a non-empty but correctly sized block
-        let mut maybe_non_empties = unsafe { self.indexed_unchecked(index).lock() };
-        if !maybe_non_empties.is_empty() {
-            return maybe_non_empties.pop()
-        } else {
-            drop(maybe_non_empties);
+    /// Purge the empties that have been empty for long enough, unless that was
+    /// checked less than a purge delay ago.
+    fn maybe_purge(&self) {
+        let now = monotonic_ns();
+        let next = NEXT_PURGE.load(Ordering::Relaxed);
+        if now < next {
+            return
+        }
+        let delay = purge_delay();
+        let after = now.saturating_add(delay);
+        if NEXT_PURGE.compare_exchange(next, after, Ordering::Relaxed, Ordering::Relaxed).is_ok() {
+            self.purge(delay);
+        }
+    }
+
+    /// Give the physical pages of the blocks that have been empty for at least
+    /// `delay` ns back to the OS. Returns how many were purged.
+    pub fn purge(&self, delay: u64) -> usize {
+        let now = monotonic_ns();
+        let mut purged = 0;
+        for empties in &[&self.small_empties, &self.large_empties] {
+            empties.for_each(|header| {
+                if unsafe { &mut *header }.purge_if_idle(now, delay) {
+                    purged += 1;
+                }
+            });
         }
+        purged
+    }
 
+    /// Request an empty block formatted for the bucket specified by index, if
+    /// one can be got, otherwise None. Blocks in the bucket's own list are
+    /// abandoned ones, for `adopt`.
+    pub fn request(&self, index: usize) -> Option<&'static UnsafeCell<BlockHeader>> {
         // Try to find an empty block
-        let mut maybe_empties = self.empties.lock();
-        if !maybe_empties.is_empty() {
-            let mut b = maybe_empties.pop();
-            drop(maybe_empties);
+        let kind = SegmentType::from_bucket(index);
+        let empties = self.empties(kind);
+        let mut b = empties.pop(|header| {
+            if unsafe { &*header.get() }.get_segment().block_reused() {
+                self.cached(kind).fetch_sub(1, Ordering::Relaxed);
+            }
+        });
+        if b.is_some() {
             // format empty block
             let bh = unsafe {
                 mem::transmute::<_, &'static mut BlockHeader>(
@@ -124,14 +499,17 @@ impl TopLevel {
         //     "TINY_BUCKETS={}, SMALL_BUCKETS={}, LARGE_BUCKETS={}, BUCKETS={}",
         //     TINY_SMALL_BUCKETS, SMALL_BUCKETS, LARGE_BUCKETS, BUCKETS
         // );
-        for block_header in SegmentHeader::new(SegmentType::from_bucket(index))?.into_iter() {
+        let segment = SegmentHeader::new(kind)?;
+        for block_header in (0..segment.num_blocks()).map(|i| unsafe { segment.block_header(i) }) {
             match first {
                 None => first = Some(block_header),
-                _ => maybe_empties.push(block_header),
+                // the first block stays in use, so this never runs out
+                _ => {
+                    empties.push(block_header, || segment.block_emptied());
+                },
             };
             self.total_count.fetch_add(1, Ordering::Relaxed);
         }
-        drop(maybe_empties);
 
         // format empty block
         let bh = unsafe {
@@ -145,10 +523,7 @@ impl TopLevel {
 }
 
 impl TopLevel {
-    pub unsafe fn indexed_unchecked(
-        &self,
-        index: usize,
-    ) -> &'_ Mutex<Vec<&'static UnsafeCell<BlockHeader>>> {
+    pub unsafe fn indexed_unchecked(&self, index: usize) -> &'_ ShardedBlockList {
         &self.buckets.get_unchecked(index)
     }
 
@@ -156,19 +531,61 @@ impl TopLevel {
         &self,
         index: usize,
     ) -> Option<&'static UnsafeCell<BlockHeader>> {
-        let mut guard = self.indexed_unchecked(index).lock();
-        guard.pop()
+        self.indexed_unchecked(index).pop(|_| {})
     }
 }
 
+// stored inline rather than in an Arc: the top-level must be reachable without
+// allocating once aura is the global allocator
 lazy_static! {
-    static ref TOP_LEVEL: Arc<TopLevel> = Arc::new(TopLevel::new());
+    static ref TOP_LEVEL: TopLevel = TopLevel::new();
 }
 // static mut TOP_LEVEL: Option<Arc<TopLevel>> = None;
 
 // pub fn init_top_level() { unsafe { TOP_LEVEL =
 // Some(Arc::new(TopLevel::new())) }; }
-pub fn get() -> Arc<TopLevel> {
+pub fn get() -> &'static TopLevel {
     // unsafe { TOP_LEVEL.as_ref().unwrap_unchecked().clone() }
-    TOP_LEVEL.clone()
+    &TOP_LEVEL
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+    use std::thread;
+
+    use super::{ShardedBlockList, SHARDS};
+    use crate::segment::{SegmentHeader, SegmentType};
+
+    #[test]
+    fn sharded_list_is_one_list() {
+        let segment = SegmentHeader::new(SegmentType::Small).unwrap();
+        let (addr, blocks) = (segment as *const SegmentHeader as usize, segment.num_blocks());
+        let list: &'static ShardedBlockList = Box::leak(Box::new(ShardedBlockList::new()));
+
+        // pushed from wherever those threads ran...
+        let handles = (0..SHARDS).map(|shard| {
+            thread::spawn(move || {
+                let segment = unsafe { &*(addr as *const SegmentHeader) };
+                for i in (shard..blocks).step_by(SHARDS) {
+                    list.push(unsafe { segment.block_header(i) }, || ());
+                }
+            })
+        });
+        handles.collect::<Vec<_>>().into_iter().for_each(|handle| handle.join().unwrap());
+        assert_eq!(list.len(), blocks);
+
+        // ...and all of them popped from here
+        let mut popped = HashSet::new();
+        while let Some(header) = list.pop(|_| ()) {
+            assert!(popped.insert(header.get()));
+        }
+        assert_eq!(popped.len(), blocks);
+
+        for i in 0..blocks {
+            list.push(unsafe { segment.block_header(i) }, || ());
+        }
+        assert_eq!(list.lock_all().remove_segment(segment), blocks);
+        assert_eq!(list.len(), 0);
+    }
 }
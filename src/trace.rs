@@ -0,0 +1,78 @@
+//! Allocation tracing (the `trace` feature): every alloc and free on a block
+//! is recorded in a fixed ring buffer of the calling thread, which can be read
+//! back or dumped to stderr on demand. Recording neither allocates nor does
+//! I/O, so it's safe underneath the global allocator.
+
+use std::cell::UnsafeCell;
+use std::io::Write;
+
+use super::heap;
+
+/// How many of a thread's most recent events its ring buffer holds.
+pub const TRACE_EVENTS: usize = 256;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TraceKind {
+    Alloc,
+    Free,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct TraceEvent {
+    pub kind: TraceKind,
+    pub thread: u64,
+    /// Address of the block header.
+    pub block: usize,
+    /// The block's allocation count before the event.
+    pub prev_count: usize,
+}
+
+const NO_EVENT: TraceEvent =
+    TraceEvent { kind: TraceKind::Alloc, thread: 0, block: 0, prev_count: 0 };
+
+struct Ring {
+    events: [TraceEvent; TRACE_EVENTS],
+    // events recorded so far; the newest is at (next - 1) % TRACE_EVENTS
+    next: usize,
+}
+
+thread_local! {
+    static RING: UnsafeCell<Ring> =
+        UnsafeCell::new(Ring { events: [NO_EVENT; TRACE_EVENTS], next: 0 });
+}
+
+pub fn record(kind: TraceKind, block: usize, prev_count: usize) {
+    let thread = heap::thread_id().get();
+    RING.with(|ring| {
+        let ring = unsafe { &mut *ring.get() };
+        ring.events[ring.next % TRACE_EVENTS] = TraceEvent { kind, thread, block, prev_count };
+        ring.next += 1;
+    });
+}
+
+/// Run `f` on the calling thread's recorded events, oldest first.
+pub fn for_each_event(mut f: impl FnMut(&TraceEvent)) {
+    RING.with(|ring| {
+        let ring = unsafe { &*ring.get() };
+        (ring.next.saturating_sub(TRACE_EVENTS)..ring.next)
+            .for_each(|i| f(&ring.events[i % TRACE_EVENTS]));
+    });
+}
+
+/// Write the calling thread's recorded events to stderr, oldest first. Each
+/// line is formatted on the stack and written straight to the fd, so this can
+/// be called from inside the allocator (e.g. from a debugger).
+pub fn dump() {
+    for_each_event(|event| {
+        let mut line = [0u8; 128];
+        let mut cursor = &mut line[..];
+        let _ = writeln!(
+            cursor,
+            "{}T {:?} prev_cnt={} on {:#x}",
+            event.thread, event.kind, event.prev_count, event.block
+        );
+        let unused = cursor.len();
+        let len = line.len() - unused;
+        unsafe { libc::write(libc::STDERR_FILENO, line.as_ptr() as *const libc::c_void, len) };
+    });
+}
@@ -1,5 +1,13 @@
 use std::{intrinsics, mem};
 
+/// Monotonic clock in nanoseconds; unlike `Instant`, usable from inside the
+/// allocator and storable in an atomic.
+pub fn monotonic_ns() -> u64 {
+    let mut ts: libc::timespec = unsafe { mem::zeroed() };
+    unsafe { libc::clock_gettime(libc::CLOCK_MONOTONIC, &mut ts) };
+    ts.tv_sec as u64 * 1_000_000_000 + ts.tv_nsec as u64
+}
+
 macro_rules! extrinsic_bsr_variant {
     ($func_name: ident, $typ: ty) => {
         pub const fn $func_name(x: $typ) -> usize {
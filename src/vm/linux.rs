@@ -0,0 +1,1045 @@
+use std::io;
+use std::mem;
+use std::ptr;
+use std::sync::atomic::{AtomicU32, AtomicU8, AtomicUsize, Ordering};
+
+use parking_lot::{Mutex, Once};
+
+use super::{page_size, VirtualRegion};
+use crate::constants::{GB, MB};
+use crate::Error;
+
+/// All regions live inside arenas, ranges of address space reserved up front
+/// and backed by a single memfd; more arenas are reserved as the ones before
+/// fill up. A page's offset is `k * ARENA_SIZE + off` for the page at `off`
+/// into arena `k`, and the page is, unless it has been remapped, backed by the
+/// file page at that same offset. The arenas record which file page every one
+/// of their pages maps, so that `from_raw_parts` (and so the meshing code) can
+/// find the physical pages behind an address, and so that a file page is
+/// punched out as soon as nothing maps it any more.
+///
+/// Since every page is a shared mapping, a forked child would share the heap
+/// with its parent; the child copies the file and maps the copy over the
+/// arenas before either of them goes on (see `split_from_parent`).
+const ARENA_SIZE: usize = 64 * GB;
+const ARENA_ALIGN: usize = 4 * MB;
+/// How many arenas can be reserved, at most; the page tables cover them all.
+const MAX_ARENAS: usize = 16;
+/// Capacity of the free span list; past that the smallest spans are leaked
+/// (their physical pages are still released). Neighbouring spans are merged,
+/// so only spans with something in use between them take up entries.
+const ARENA_FREE_SPANS: usize = 256;
+
+#[repr(C)]
+pub struct LinuxVMRegion {
+    begin: *mut u8,
+    size: usize,
+    // offset into the arena memfd of the pages mapped at `begin`
+    offset: usize,
+}
+
+struct FreeSpans {
+    // offset past which the last arena has never been used
+    bump: usize,
+    arenas: usize,
+    len: usize,
+    spans: [(usize, usize); ARENA_FREE_SPANS],
+}
+
+struct Arenas {
+    fd: libc::c_int,
+    // start of each arena reserved so far, 0 past those; only set under the
+    // span lock
+    bases: [AtomicUsize; MAX_ARENAS],
+    // number of live mappings of each file page
+    refs: *const AtomicU32,
+    // file page mapped at each arena page, plus one; 0 if nothing is
+    backing: *const AtomicU32,
+    // protection of each arena page, as `prot_bits` of it; 0 is read-write
+    prots: *const AtomicU8,
+    spans: Mutex<FreeSpans>,
+}
+
+static mut ARENAS: Option<Arenas> = None;
+static ARENAS_INIT: Once = Once::new();
+
+// the parent waits on this until a child it forked has a heap of its own
+static mut FORK_PIPE: [libc::c_int; 2] = [-1, -1];
+
+fn last_error() -> Error {
+    let err = io::Error::last_os_error();
+    match err.raw_os_error() {
+        Some(libc::ENOMEM) | Some(libc::ENOSPC) => Error::OutOfMemory,
+        _ => Error::Generic(err.to_string()),
+    }
+}
+
+const fn align_up(x: usize, align: usize) -> usize { (x + align - 1) & !(align - 1) }
+
+const fn prot_bits(read: bool, write: bool) -> u8 { !read as u8 | (!write as u8) << 1 }
+
+fn prot_flags(bits: u8) -> libc::c_int {
+    match bits {
+        0 => libc::PROT_READ | libc::PROT_WRITE,
+        1 => libc::PROT_WRITE,
+        2 => libc::PROT_READ,
+        _ => libc::PROT_NONE,
+    }
+}
+
+fn create_memfd() -> Result<libc::c_int, Error> {
+    // no CString: this can run underneath the global allocator
+    let name = b"aura-arena\0".as_ptr() as *const libc::c_char;
+    match unsafe { libc::memfd_create(name, libc::MFD_CLOEXEC) } {
+        -1 => Err(last_error()),
+        fd => Ok(fd),
+    }
+}
+
+/// Reserve (but don't commit) `size` bytes of address space aligned to
+/// `align`, by over-allocating and trimming.
+unsafe fn reserve_aligned(size: usize, align: usize) -> Result<*mut u8, Error> {
+    let raw = libc::mmap(
+        ptr::null_mut(),
+        size + align,
+        libc::PROT_NONE,
+        libc::MAP_PRIVATE | libc::MAP_ANONYMOUS | libc::MAP_NORESERVE,
+        -1,
+        0,
+    );
+    if raw == libc::MAP_FAILED {
+        return Err(last_error())
+    }
+    let raw = raw as usize;
+    let aligned = align_up(raw, align);
+    if aligned > raw {
+        libc::munmap(raw as *mut libc::c_void, aligned - raw);
+    }
+    let tail = raw + size + align - (aligned + size);
+    if tail > 0 {
+        libc::munmap((aligned + size) as *mut libc::c_void, tail);
+    }
+    Ok(aligned as *mut u8)
+}
+
+impl Arenas {
+    fn get() -> &'static Arenas {
+        ARENAS_INIT.call_once(|| unsafe {
+            ARENAS = Some(Arenas::init());
+            // glibc has room for this without allocating
+            let (prepare, parent, child) =
+                (prepare_fork, after_fork_in_parent, after_fork_in_child);
+            if 0 != libc::pthread_atfork(Some(prepare), Some(parent), Some(child)) {
+                panic!("couldn't register the arena fork handlers");
+            }
+        });
+        unsafe { ARENAS.as_ref().unwrap_unchecked() }
+    }
+
+    unsafe fn init() -> Arenas {
+        let fd = match create_memfd() {
+            Ok(fd) => fd,
+            Err(e) => panic!("couldn't create arena memfd: {}", e),
+        };
+        let arenas = Arenas {
+            fd,
+            bases: Default::default(),
+            refs: Self::page_table(),
+            backing: Self::page_table(),
+            prots: Self::page_table(),
+            spans: Mutex::new(FreeSpans {
+                bump: 0,
+                arenas: 0,
+                len: 0,
+                spans: [(0, 0); ARENA_FREE_SPANS],
+            }),
+        };
+        if let Err(e) = arenas.add_arena(&mut arenas.spans.lock()) {
+            panic!("couldn't reserve arena: {}", e);
+        }
+        arenas
+    }
+
+    /// Reserve another arena, and carry on bumping from its start; what the
+    /// last one had left becomes a free span.
+    fn add_arena(&self, spans: &mut FreeSpans) -> Result<(), Error> {
+        let k = spans.arenas;
+        if k == MAX_ARENAS {
+            return Err(Error::OutOfMemory)
+        }
+        if unsafe { libc::ftruncate(self.fd, ((k + 1) * ARENA_SIZE) as libc::off_t) } != 0 {
+            return Err(last_error())
+        }
+        let base = unsafe { reserve_aligned(ARENA_SIZE, ARENA_ALIGN)? };
+        self.bases[k].store(base as usize, Ordering::Release);
+        let bump = spans.bump;
+        Self::push_span(spans, bump, k * ARENA_SIZE - bump);
+        spans.bump = k * ARENA_SIZE;
+        spans.arenas = k + 1;
+        Ok(())
+    }
+
+    fn page_table_size<T>() -> usize { (MAX_ARENAS * ARENA_SIZE / page_size()) * mem::size_of::<T>() }
+
+    /// One `T` per page of every arena there can be, zeroed; only the parts
+    /// that get used are ever committed.
+    unsafe fn page_table<T>() -> *const T {
+        let table = libc::mmap(
+            ptr::null_mut(),
+            Self::page_table_size::<T>(),
+            libc::PROT_READ | libc::PROT_WRITE,
+            libc::MAP_PRIVATE | libc::MAP_ANONYMOUS | libc::MAP_NORESERVE,
+            -1,
+            0,
+        );
+        if table == libc::MAP_FAILED {
+            panic!("couldn't allocate arena page table: {}", last_error());
+        }
+        table as *const T
+    }
+
+    fn offset_of(&self, addr: *mut u8) -> usize {
+        let addr = addr as usize;
+        for (k, base) in self.bases.iter().enumerate() {
+            let base = base.load(Ordering::Acquire);
+            if base != 0 && addr >= base && addr < base + ARENA_SIZE {
+                return k * ARENA_SIZE + addr - base
+            }
+        }
+        panic!("{:#x} isn't in any arena", addr)
+    }
+
+    fn addr_of(&self, offset: usize) -> *mut u8 {
+        let base = self.bases[offset / ARENA_SIZE].load(Ordering::Acquire);
+        debug_assert_ne!(base, 0);
+        (base + offset % ARENA_SIZE) as *mut u8
+    }
+
+    fn page_refs(&self, offset: usize) -> &AtomicU32 {
+        unsafe { &*self.refs.add(offset / page_size()) }
+    }
+
+    fn page_backing(&self, offset: usize) -> &AtomicU32 {
+        unsafe { &*self.backing.add(offset / page_size()) }
+    }
+
+    fn page_prot(&self, offset: usize) -> &AtomicU8 {
+        unsafe { &*self.prots.add(offset / page_size()) }
+    }
+
+    fn set_prot(&self, va_offset: usize, size: usize, read: bool, write: bool) {
+        for off in (va_offset..va_offset + size).step_by(page_size()) {
+            self.page_prot(off).store(prot_bits(read, write), Ordering::SeqCst);
+        }
+    }
+
+    /// File offset of the page mapped at `addr`: its own unless remapped.
+    fn file_offset_of(&self, addr: *mut u8) -> usize {
+        let offset = self.offset_of(addr);
+        match self.page_backing(offset).load(Ordering::SeqCst) {
+            0 => offset,
+            page => (page as usize - 1) * page_size(),
+        }
+    }
+
+    fn is_unreferenced(&self, offset: usize, size: usize) -> bool {
+        (offset..offset + size)
+            .step_by(page_size())
+            .all(|off| self.page_refs(off).load(Ordering::SeqCst) == 0)
+    }
+
+    /// Find `size` bytes of address space in the arena, reusing freed spans
+    /// whose pages are no longer mapped anywhere.
+    fn reserve(&self, size: usize, align: usize) -> Result<usize, Error> {
+        let align = align.max(page_size());
+        let mut spans = self.spans.lock();
+        for i in 0..spans.len {
+            let (off, len) = spans.spans[i];
+            let start = align_up(off, align);
+            if start + size > off + len || !self.is_unreferenced(start, size) {
+                continue
+            }
+            spans.len -= 1;
+            spans.spans[i] = spans.spans[spans.len];
+            Self::push_span(&mut spans, off, start - off);
+            Self::push_span(&mut spans, start + size, off + len - (start + size));
+            return Ok(start)
+        }
+        if size > ARENA_SIZE {
+            return Err(Error::OutOfMemory)
+        }
+        let mut start = align_up(spans.bump, align);
+        if start + size > spans.arenas * ARENA_SIZE {
+            self.add_arena(&mut spans)?;
+            start = spans.bump;
+        }
+        let bump = spans.bump;
+        Self::push_span(&mut spans, bump, start - bump);
+        spans.bump = start + size;
+        Ok(start)
+    }
+
+    /// Take the address space at `offset` if it's free, e.g. to grow the
+    /// region just before it in place. Returns whether it was.
+    fn claim(&self, offset: usize, size: usize) -> bool {
+        if 0 == offset % ARENA_SIZE {
+            // the next arena doesn't follow on in the address space
+            return false
+        }
+        let mut spans = self.spans.lock();
+        if offset == spans.bump {
+            if offset + size > spans.arenas * ARENA_SIZE {
+                return false
+            }
+            spans.bump += size;
+            return true
+        }
+        for i in 0..spans.len {
+            let (off, len) = spans.spans[i];
+            if offset < off || offset + size > off + len || !self.is_unreferenced(offset, size) {
+                continue
+            }
+            spans.len -= 1;
+            spans.spans[i] = spans.spans[spans.len];
+            Self::push_span(&mut spans, off, offset - off);
+            Self::push_span(&mut spans, offset + size, off + len - (offset + size));
+            return true
+        }
+        false
+    }
+
+    fn release(&self, offset: usize, size: usize) {
+        Self::push_span(&mut self.spans.lock(), offset, size);
+    }
+
+    fn push_span(spans: &mut FreeSpans, mut offset: usize, mut size: usize) {
+        if size == 0 {
+            return
+        }
+        // merge with the spans either side, within the same arena
+        let mut i = 0;
+        while i < spans.len {
+            let (off, len) = spans.spans[i];
+            if off + len == offset && 0 != offset % ARENA_SIZE {
+                offset = off;
+                size += len;
+            } else if offset + size == off && 0 != off % ARENA_SIZE {
+                size += len;
+            } else {
+                i += 1;
+                continue
+            }
+            spans.len -= 1;
+            spans.spans[i] = spans.spans[spans.len];
+        }
+        if spans.len == ARENA_FREE_SPANS {
+            // full: keep the larger spans (alignment padding tends to be small)
+            let (smallest, &(_, smallest_size)) =
+                spans.spans.iter().enumerate().min_by_key(|(_, &(_, len))| len).unwrap();
+            if smallest_size < size {
+                spans.spans[smallest] = (offset, size);
+            }
+            return
+        }
+        let len = spans.len;
+        spans.spans[len] = (offset, size);
+        spans.len += 1;
+    }
+
+    /// Record that the arena pages at `va_offset` now map the file pages at
+    /// `file_offset` (or nothing), punching out the file pages they used to
+    /// map if nothing else maps those.
+    fn rebind(
+        &self,
+        va_offset: usize,
+        file_offset: Option<usize>,
+        size: usize,
+    ) -> Result<(), Error> {
+        let page = page_size();
+        // contiguous run of file pages waiting to be punched
+        let mut run: Option<(usize, usize)> = None;
+        for off in (0..size).step_by(page) {
+            let new = match file_offset {
+                Some(file_offset) => {
+                    self.page_refs(file_offset + off).fetch_add(1, Ordering::SeqCst);
+                    ((file_offset + off) / page + 1) as u32
+                },
+                None => 0,
+            };
+            let old = self.page_backing(va_offset + off).swap(new, Ordering::SeqCst);
+            // mapped read-write, or not at all
+            self.page_prot(va_offset + off).store(0, Ordering::SeqCst);
+            if old == 0 {
+                continue
+            }
+            let old = (old as usize - 1) * page;
+            if self.page_refs(old).fetch_sub(1, Ordering::SeqCst) != 1 {
+                continue
+            }
+            run = match run {
+                Some((start, end)) if end == old => Some((start, end + page)),
+                Some((start, end)) => {
+                    self.punch(start, end - start)?;
+                    Some((old, old + page))
+                },
+                None => Some((old, old + page)),
+            };
+        }
+        match run {
+            Some((start, end)) => self.punch(start, end - start),
+            None => Ok(()),
+        }
+    }
+
+    /// Punch out the file pages mapped at `va_offset` that nothing else maps;
+    /// they read back as zeroes. Returns whether all of them were.
+    fn purge(&self, va_offset: usize, size: usize) -> Result<bool, Error> {
+        let page = page_size();
+        let mut all = true;
+        let mut run: Option<(usize, usize)> = None;
+        for off in (0..size).step_by(page) {
+            let file_page = match self.page_backing(va_offset + off).load(Ordering::SeqCst) {
+                0 => continue,
+                file_page => (file_page as usize - 1) * page,
+            };
+            if self.page_refs(file_page).load(Ordering::SeqCst) != 1 {
+                all = false;
+                continue
+            }
+            run = match run {
+                Some((start, end)) if end == file_page => Some((start, end + page)),
+                Some((start, end)) => {
+                    self.punch(start, end - start)?;
+                    Some((file_page, file_page + page))
+                },
+                None => Some((file_page, file_page + page)),
+            };
+        }
+        if let Some((start, end)) = run {
+            self.punch(start, end - start)?;
+        }
+        Ok(all)
+    }
+
+    fn punch(&self, offset: usize, size: usize) -> Result<(), Error> {
+        let r = unsafe {
+            libc::fallocate(
+                self.fd,
+                libc::FALLOC_FL_PUNCH_HOLE | libc::FALLOC_FL_KEEP_SIZE,
+                offset as libc::off_t,
+                size as libc::off_t,
+            )
+        };
+        if r != 0 {
+            return Err(last_error())
+        }
+        Ok(())
+    }
+
+    unsafe fn map(
+        &self,
+        target: *mut u8,
+        offset: usize,
+        size: usize,
+        shared: bool,
+    ) -> Result<*mut u8, Error> {
+        debug_assert!(!target.is_null());
+        debug_assert_ne!(size, 0);
+        let flags = if shared { libc::MAP_SHARED } else { libc::MAP_PRIVATE } | libc::MAP_FIXED;
+        let addr = libc::mmap(
+            target as *mut libc::c_void,
+            size,
+            libc::PROT_READ | libc::PROT_WRITE,
+            flags,
+            self.fd,
+            offset as libc::off_t,
+        );
+        if addr == libc::MAP_FAILED {
+            return Err(last_error())
+        }
+        self.rebind(self.offset_of(target), Some(offset), size)?;
+        Ok(addr as *mut u8)
+    }
+
+    /// Map whatever file pages back the arena pages at `source` at `target`
+    /// as well, one run of consecutive file pages at a time.
+    unsafe fn map_like(&self, target: *mut u8, source: *mut u8, size: usize) -> Result<(), Error> {
+        let page = page_size();
+        let mut done = 0;
+        while done < size {
+            let offset = self.file_offset_of(source.add(done));
+            let mut len = page;
+            while done + len < size && self.file_offset_of(source.add(done + len)) == offset + len {
+                len += page;
+            }
+            self.map(target.add(done), offset, len, true)?;
+            done += len;
+        }
+        Ok(())
+    }
+
+    /// Replace the mapping with inaccessible, uncommitted address space so the
+    /// range stays reserved for its arena.
+    unsafe fn unmap(&self, begin: *mut u8, size: usize) -> Result<(), Error> {
+        let addr = libc::mmap(
+            begin as *mut libc::c_void,
+            size,
+            libc::PROT_NONE,
+            libc::MAP_PRIVATE | libc::MAP_ANONYMOUS | libc::MAP_NORESERVE | libc::MAP_FIXED,
+            -1,
+            0,
+        );
+        if addr == libc::MAP_FAILED {
+            return Err(last_error())
+        }
+        self.rebind(self.offset_of(begin), None, size)
+    }
+
+    /// Give a forked child a heap of its own: copy the file pages into a new
+    /// memfd, and map that over every arena page that maps one, with the
+    /// page's protection. Regions made by `dup_to` come out of it shared like
+    /// the rest. Runs with the span lock held, so no offsets move meanwhile.
+    unsafe fn split_from_parent(&self) -> Result<(), Error> {
+        let spans = &*self.spans.data_ptr();
+        // nothing past the bump has been used
+        let used = spans.bump;
+        let fd = create_memfd()?;
+        let copied = self
+            .copy_file(fd, spans.arenas * ARENA_SIZE, used)
+            .and_then(|()| self.map_file(fd, used));
+        // the mappings keep the new file; the fd number stays the same
+        let r = copied.and_then(|()| match libc::dup3(fd, self.fd, libc::O_CLOEXEC) {
+            -1 => Err(last_error()),
+            _ => Ok(()),
+        });
+        libc::close(fd);
+        r
+    }
+
+    /// Map `fd` over every arena page below `used` that maps a file page, at
+    /// the same file offset and with the page's protection, one run of pages
+    /// mapping consecutive file pages alike at a time.
+    unsafe fn map_file(&self, fd: libc::c_int, used: usize) -> Result<(), Error> {
+        let page = page_size();
+        let file_page_at = |va: usize| match self.page_backing(va).load(Ordering::SeqCst) {
+            0 => None,
+            file_page => {
+                Some(((file_page as usize - 1) * page, self.page_prot(va).load(Ordering::SeqCst)))
+            },
+        };
+        let mut va = 0;
+        while va < used {
+            let (file_offset, prot) = match file_page_at(va) {
+                Some(file_page) => file_page,
+                None => {
+                    va += page;
+                    continue
+                },
+            };
+            let mut len = page;
+            while va + len < used
+                && 0 != (va + len) % ARENA_SIZE
+                && file_page_at(va + len) == Some((file_offset + len, prot))
+            {
+                len += page;
+            }
+            let addr = libc::mmap(
+                self.addr_of(va) as *mut libc::c_void,
+                len,
+                prot_flags(prot),
+                libc::MAP_SHARED | libc::MAP_FIXED,
+                fd,
+                file_offset as libc::off_t,
+            );
+            if addr == libc::MAP_FAILED {
+                return Err(last_error())
+            }
+            va += len;
+        }
+        Ok(())
+    }
+
+    /// Copy what has been written of the first `used` bytes of the file into
+    /// `fd`, sized to `size`; holes (punched out pages) stay holes.
+    unsafe fn copy_file(&self, fd: libc::c_int, size: usize, used: usize) -> Result<(), Error> {
+        if libc::ftruncate(fd, size as libc::off_t) != 0 {
+            return Err(last_error())
+        }
+        if used == 0 {
+            return Ok(())
+        }
+        let src = libc::mmap(
+            ptr::null_mut(),
+            used,
+            libc::PROT_READ,
+            libc::MAP_SHARED | libc::MAP_NORESERVE,
+            self.fd,
+            0,
+        );
+        if src == libc::MAP_FAILED {
+            return Err(last_error())
+        }
+        let mut r = Ok(());
+        let mut pos = 0;
+        while pos < used {
+            let data = libc::lseek(self.fd, pos as libc::off_t, libc::SEEK_DATA);
+            if data < 0 {
+                // ENXIO: nothing but holes from here on
+                break
+            }
+            let hole = libc::lseek(self.fd, data, libc::SEEK_HOLE) as usize;
+            let (mut start, end) = (data as usize, hole.min(used));
+            while start < end {
+                let n = libc::pwrite(
+                    fd,
+                    (src as *const u8).add(start) as *const libc::c_void,
+                    end - start,
+                    start as libc::off_t,
+                );
+                if n < 0 {
+                    r = Err(last_error());
+                    break
+                }
+                start += n as usize;
+            }
+            if r.is_err() {
+                break
+            }
+            pos = hole;
+        }
+        libc::munmap(src, used);
+        r
+    }
+}
+
+/// Hold the span lock across `fork()`, and set up the pipe the parent waits on.
+extern "C" fn prepare_fork() {
+    let arenas = Arenas::get();
+    mem::forget(arenas.spans.lock());
+    unsafe {
+        if 0 != libc::pipe2(FORK_PIPE.as_mut_ptr(), libc::O_CLOEXEC) {
+            FORK_PIPE = [-1, -1];
+        }
+    }
+}
+
+/// The child still maps the parent's pages until it's done copying them: wait
+/// for it to say so, or to exit.
+extern "C" fn after_fork_in_parent() {
+    unsafe {
+        let [rx, tx] = FORK_PIPE;
+        libc::close(tx);
+        let mut done = 0u8;
+        while libc::read(rx, &mut done as *mut u8 as *mut libc::c_void, 1) < 0
+            && Some(libc::EINTR) == io::Error::last_os_error().raw_os_error()
+        {}
+        libc::close(rx);
+        Arenas::get().spans.force_unlock();
+    }
+}
+
+extern "C" fn after_fork_in_child() {
+    let arenas = Arenas::get();
+    unsafe {
+        let [rx, tx] = FORK_PIPE;
+        libc::close(rx);
+        if let Err(e) = arenas.split_from_parent() {
+            // carrying on would have parent and child corrupt each other's heap
+            panic!("couldn't copy the heap for a forked child: {}", e);
+        }
+        libc::write(tx, &1u8 as *const u8 as *const libc::c_void, 1);
+        libc::close(tx);
+        arenas.spans.force_unlock();
+    }
+}
+
+/// Only arenas of a test's own are ever dropped; the global ones live as long
+/// as the process.
+impl Drop for Arenas {
+    fn drop(&mut self) {
+        unsafe {
+            for base in &self.bases {
+                let base = base.load(Ordering::Acquire);
+                if base != 0 {
+                    libc::munmap(base as *mut libc::c_void, ARENA_SIZE);
+                }
+            }
+            libc::munmap(self.refs as *mut libc::c_void, Self::page_table_size::<AtomicU32>());
+            libc::munmap(self.backing as *mut libc::c_void, Self::page_table_size::<AtomicU32>());
+            libc::munmap(self.prots as *mut libc::c_void, Self::page_table_size::<AtomicU8>());
+            libc::close(self.fd);
+        }
+    }
+}
+
+impl LinuxVMRegion {
+    fn _overlay(
+        &self,
+        offset: usize,
+        size: usize,
+        target: *mut u8,
+        shared: bool,
+    ) -> Result<Self, Error> {
+        let arenas = Arenas::get();
+        let offset = self.offset + offset;
+        let addr = unsafe { arenas.map(target, offset, size, shared)? };
+        Ok(LinuxVMRegion { begin: addr, size, offset })
+    }
+
+    fn _overlay_aligned(
+        &self,
+        offset: usize,
+        size: usize,
+        target_align: usize,
+        shared: bool,
+    ) -> Result<Self, Error> {
+        let arenas = Arenas::get();
+        let va_offset = arenas.reserve(size, target_align)?;
+        let target = arenas.addr_of(va_offset);
+        self._overlay(offset, size, target, shared).map_err(|e| {
+            arenas.release(va_offset, size);
+            e
+        })
+    }
+}
+
+impl VirtualRegion for LinuxVMRegion {
+    fn new(size: usize, align: usize) -> Result<LinuxVMRegion, Error> {
+        debug_assert!(0 == size % page_size());
+        debug_assert!(align.is_power_of_two());
+
+        let arenas = Arenas::get();
+        let offset = arenas.reserve(size, align)?;
+        let begin = arenas.addr_of(offset);
+        if let Err(e) = unsafe { arenas.map(begin, offset, size, true) } {
+            arenas.release(offset, size);
+            return Err(e)
+        }
+        Ok(LinuxVMRegion { begin, size, offset })
+    }
+
+    unsafe fn from_raw_parts(addr: *mut u8, size: usize) -> LinuxVMRegion {
+        // whole pages: guarded blocks' bodies are a page short of a power of two
+        debug_assert!(0 == size % page_size());
+
+        LinuxVMRegion { begin: addr, size, offset: Arenas::get().file_offset_of(addr) }
+    }
+
+    fn base(&self) -> *mut u8 { self.begin }
+    fn size(&self) -> usize { self.size }
+
+    fn map_to(&self, offset: usize, size: usize, target: *mut u8) -> Result<Self, Error> {
+        self._overlay(offset, size, target, true)
+    }
+    fn map_aligned(&self, offset: usize, size: usize, target_align: usize) -> Result<Self, Error> {
+        self._overlay_aligned(offset, size, target_align, true)
+    }
+
+    fn dup_to(&self, offset: usize, size: usize, target: *mut u8) -> Result<Self, Error> {
+        self._overlay(offset, size, target, false)
+    }
+    fn dup_aligned(&self, offset: usize, size: usize, target_align: usize) -> Result<Self, Error> {
+        self._overlay_aligned(offset, size, target_align, false)
+    }
+
+    /// The new pages are the file pages of the address space they go in.
+    fn extend(&mut self, size: usize) -> Result<(), Error> {
+        debug_assert!(size > self.size && 0 == size % page_size());
+        let arenas = Arenas::get();
+        let tail = arenas.offset_of(self.begin) + self.size;
+        let extra = size - self.size;
+        if !arenas.claim(tail, extra) {
+            return Err(Error::OutOfMemory)
+        }
+        if let Err(e) = unsafe { arenas.map(arenas.addr_of(tail), tail, extra, true) } {
+            arenas.release(tail, extra);
+            return Err(e)
+        }
+        self.size = size;
+        Ok(())
+    }
+
+    /// The region's pages are mapped at the new address too before they're
+    /// unmapped at the old one, so they're never punched out in between.
+    fn remap(&mut self, size: usize, align: usize) -> Result<(), Error> {
+        debug_assert!(size > self.size && 0 == size % page_size());
+        let arenas = Arenas::get();
+        let va_offset = arenas.reserve(size, align)?;
+        let target = arenas.addr_of(va_offset);
+        let mapped = unsafe {
+            arenas.map_like(target, self.begin, self.size).and_then(|()| {
+                let tail = va_offset + self.size;
+                arenas.map(target.add(self.size), tail, size - self.size, true).map(|_| ())
+            })
+        };
+        if let Err(e) = mapped {
+            unsafe { arenas.unmap(target, size)? };
+            arenas.release(va_offset, size);
+            return Err(e)
+        }
+        unsafe { arenas.unmap(self.begin, self.size)? };
+        arenas.release(arenas.offset_of(self.begin), self.size);
+        self.begin = target;
+        self.offset = arenas.file_offset_of(target);
+        self.size = size;
+        Ok(())
+    }
+
+    fn shrink(&mut self, size: usize) -> Result<(), Error> {
+        debug_assert!(size < self.size && 0 == size % page_size());
+        let arenas = Arenas::get();
+        let tail = unsafe { self.begin.add(size) };
+        unsafe { arenas.unmap(tail, self.size - size)? };
+        arenas.release(arenas.offset_of(tail), self.size - size);
+        self.size = size;
+        Ok(())
+    }
+
+    /// Give the region fresh, zeroed pages of its own. The pages the region
+    /// was backed by before stay alive for as long as something else maps
+    /// them; the region's own file pages must not be mapped elsewhere.
+    fn detach(&mut self) -> Result<(), Error> {
+        let arenas = Arenas::get();
+        unsafe { arenas.unmap(self.begin, self.size)? };
+        let offset = arenas.offset_of(self.begin);
+        if !arenas.is_unreferenced(offset, self.size) {
+            panic!("detach failed: pages of {:#?} are still mapped elsewhere", self.begin);
+        }
+        unsafe { arenas.map(self.begin, offset, self.size, true)? };
+        self.offset = offset;
+        Ok(())
+    }
+
+    /// `madvise(MADV_DONTNEED)` would only drop this mapping's view of the
+    /// shared file pages; punching them out of the file is what frees them.
+    /// Pages that are also mapped elsewhere (meshed) are left alone.
+    fn purge(&mut self) -> Result<bool, Error> {
+        let arenas = Arenas::get();
+        arenas.purge(arenas.offset_of(self.begin), self.size)
+    }
+
+    fn prot(&mut self, read: bool, write: bool) -> Result<(bool, bool), Error> {
+        let flags = match (read, write) {
+            (true, true) => libc::PROT_READ | libc::PROT_WRITE,
+            (true, false) => libc::PROT_READ,
+            (false, true) => libc::PROT_WRITE,
+            (false, false) => libc::PROT_NONE,
+        };
+        match unsafe { libc::mprotect(self.begin as *mut libc::c_void, self.size, flags) } {
+            0 => {
+                let arenas = Arenas::get();
+                arenas.set_prot(arenas.offset_of(self.begin), self.size, read, write);
+                Ok((read, write))
+            },
+            _ => Err(last_error()),
+        }
+    }
+
+    fn consume(self) -> (*mut u8, usize) { (self.begin, self.size) }
+
+    /// Unmaps the region; physical pages are released once no other region
+    /// maps them. Regions produced by `map_to`/`dup_to` overlay address space
+    /// owned by another region and should be `consume`d instead.
+    fn free(self) -> Result<(), Error> {
+        let arenas = Arenas::get();
+        unsafe { arenas.unmap(self.begin, self.size)? };
+        arenas.release(arenas.offset_of(self.begin), self.size);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::VirtualRegion;
+    use super::{Arenas, FreeSpans, LinuxVMRegion, ARENA_FREE_SPANS, ARENA_SIZE};
+
+    const TEST_SIZE: usize = 4 * crate::constants::MB;
+
+    #[test]
+    fn test_spans_coalesce() {
+        let mut spans = FreeSpans { bump: 0, arenas: 2, len: 0, spans: [(0, 0); ARENA_FREE_SPANS] };
+        Arenas::push_span(&mut spans, 0, TEST_SIZE);
+        Arenas::push_span(&mut spans, 2 * TEST_SIZE, TEST_SIZE);
+        Arenas::push_span(&mut spans, TEST_SIZE, TEST_SIZE);
+        assert_eq!(&spans.spans[..spans.len], &[(0, 3 * TEST_SIZE)]);
+
+        // the next arena doesn't follow on in the address space
+        Arenas::push_span(&mut spans, ARENA_SIZE - TEST_SIZE, TEST_SIZE);
+        Arenas::push_span(&mut spans, ARENA_SIZE, TEST_SIZE);
+        assert_eq!(spans.len, 3);
+    }
+
+    #[test]
+    fn test_more_arenas() {
+        // arenas of its own, so that nothing else gets in the way; they're
+        // unmapped when it's done
+        let arenas = unsafe { Arenas::init() };
+        assert_eq!(arenas.reserve(ARENA_SIZE - TEST_SIZE, TEST_SIZE).unwrap(), 0);
+        let next = arenas.reserve(2 * TEST_SIZE, TEST_SIZE).unwrap();
+        assert_eq!(next, ARENA_SIZE);
+        assert_eq!(arenas.offset_of(arenas.addr_of(next)), next);
+        unsafe {
+            let addr = arenas.map(arenas.addr_of(next), next, 2 * TEST_SIZE, true).unwrap();
+            *addr.add(2 * TEST_SIZE - 1) = 1;
+            arenas.unmap(addr, 2 * TEST_SIZE).unwrap();
+        }
+
+        // what was left of the first arena can be grown into, but not past
+        assert!(!arenas.claim(ARENA_SIZE - TEST_SIZE, 2 * TEST_SIZE));
+        assert!(arenas.claim(ARENA_SIZE - TEST_SIZE, TEST_SIZE));
+        assert!(!arenas.claim(ARENA_SIZE, TEST_SIZE));
+    }
+
+    #[test]
+    fn test_alloc_free() {
+        let r = LinuxVMRegion::new(TEST_SIZE, TEST_SIZE).unwrap();
+        assert_eq!(r.base() as usize % TEST_SIZE, 0);
+        unsafe {
+            for i in 0..(TEST_SIZE / 0x1000) {
+                *r.base().offset(i as isize * 0x1000) = 1;
+            }
+        }
+        r.free().unwrap();
+    }
+
+    #[test]
+    fn test_map() {
+        let r1 = LinuxVMRegion::new(0x4000usize, 0x4000usize).unwrap();
+        unsafe { *r1.base() = 1 };
+        let r2 = r1.map_aligned(0, r1.size(), r1.size()).unwrap();
+        unsafe { *r2.base() = 2 };
+
+        assert_eq!(unsafe { *r1.base() }, unsafe { *r2.base() });
+
+        r1.free().unwrap();
+
+        assert_eq!(unsafe { *r2.base() }, 2);
+
+        r2.free().unwrap();
+    }
+
+    #[test]
+    fn test_map_detach() {
+        let r1 = LinuxVMRegion::new(0x4000usize, 0x4000usize).unwrap();
+        unsafe { *r1.base() = 1 };
+        let mut r2 = LinuxVMRegion::new(0x4000usize, 0x4000usize).unwrap();
+        unsafe { *r2.base() = 2 };
+
+        assert_ne!(r1.base(), r2.base());
+
+        let r3 = r1.map_to(0, r1.size(), r2.base()).unwrap();
+        assert_eq!(r3.base(), r2.base());
+
+        assert_eq!(unsafe { *r1.base() }, unsafe { *r2.base() });
+        assert_eq!(unsafe { *r2.base() }, 1);
+        unsafe { *r2.base() = 3 };
+        assert_eq!(unsafe { *r1.base() }, 3);
+
+        r2.detach().unwrap();
+
+        unsafe { *r2.base() = 4 };
+        assert_ne!(unsafe { *r1.base() }, unsafe { *r2.base() });
+
+        r1.free().unwrap();
+        r2.free().unwrap();
+        r3.consume();
+    }
+
+    #[test]
+    fn test_map_releases_overlaid() {
+        let r1 = LinuxVMRegion::new(0x4000usize, 0x4000usize).unwrap();
+        let r2 = LinuxVMRegion::new(0x4000usize, 0x4000usize).unwrap();
+        unsafe { *r2.base() = 2 };
+        let arenas = Arenas::get();
+        let r2_pages = arenas.offset_of(r2.base());
+        assert!(!arenas.is_unreferenced(r2_pages, r2.size()));
+
+        // r2's own pages aren't mapped anywhere any more
+        r1.map_to(0, r1.size(), r2.base()).unwrap().consume();
+        assert!(arenas.is_unreferenced(r2_pages, r2.size()));
+        let r1_pages = arenas.offset_of(r1.base());
+        assert_eq!(unsafe { LinuxVMRegion::from_raw_parts(r2.base(), r2.size()) }.offset, r1_pages);
+
+        let size = r1.size();
+        r1.free().unwrap();
+        assert!(!arenas.is_unreferenced(r1_pages, size));
+        r2.free().unwrap();
+        assert!(arenas.is_unreferenced(r1_pages, size));
+    }
+
+    #[test]
+    fn test_purge() {
+        let mut r1 = LinuxVMRegion::new(0x4000usize, 0x4000usize).unwrap();
+        unsafe { *r1.base() = 1 };
+        assert!(r1.purge().unwrap());
+        assert_eq!(unsafe { *r1.base() }, 0);
+
+        // shared pages stay
+        unsafe { *r1.base() = 1 };
+        let mut r2 = r1.map_aligned(0, r1.size(), r1.size()).unwrap();
+        assert!(!r2.purge().unwrap());
+        assert_eq!(unsafe { *r1.base() }, 1);
+
+        r2.free().unwrap();
+        r1.free().unwrap();
+    }
+
+    #[test]
+    fn test_extend_shrink() {
+        let mut r1 = LinuxVMRegion::new(0x4000usize, 0x4000usize).unwrap();
+        unsafe { *r1.base() = 1 };
+        let begin = r1.base();
+        r1.extend(0x8000).unwrap();
+        assert_eq!((r1.base(), r1.size()), (begin, 0x8000));
+        assert_eq!(unsafe { *r1.base() }, 1);
+        assert_eq!(unsafe { *r1.base().add(0x7fff) }, 0);
+        unsafe { *r1.base().add(0x7fff) = 2 };
+
+        // the address space after it is taken now
+        let r2 = LinuxVMRegion::new(0x4000usize, 0x4000usize).unwrap();
+        if r2.base() == unsafe { r1.base().add(r1.size()) } {
+            assert!(r1.extend(0xc000).is_err());
+        }
+
+        r1.shrink(0x4000).unwrap();
+        let arenas = Arenas::get();
+        assert!(arenas.is_unreferenced(arenas.offset_of(begin) + 0x4000, 0x4000));
+        assert_eq!(unsafe { *r1.base() }, 1);
+
+        r2.free().unwrap();
+        r1.free().unwrap();
+    }
+
+    #[test]
+    fn test_remap() {
+        let mut r1 = LinuxVMRegion::new(0x4000usize, 0x4000usize).unwrap();
+        unsafe { *r1.base() = 1 };
+        let begin = r1.base();
+        r1.remap(0x8000, 0x8000).unwrap();
+        assert_ne!(r1.base(), begin);
+        assert_eq!(r1.base() as usize % 0x8000, 0);
+        assert_eq!(unsafe { *r1.base() }, 1);
+        assert_eq!(unsafe { *r1.base().add(0x7fff) }, 0);
+
+        // the pages moved along: writes show up where they're mapped now
+        let arenas = Arenas::get();
+        let pages = arenas.offset_of(begin);
+        unsafe { *r1.base() = 2 };
+        assert!(!arenas.is_unreferenced(pages, 0x4000));
+        r1.free().unwrap();
+        assert!(arenas.is_unreferenced(pages, 0x4000));
+    }
+
+    #[test]
+    fn test_dup() {
+        let r1 = LinuxVMRegion::new(0x4000usize, 0x4000usize).unwrap();
+        unsafe { *r1.base() = 1 };
+        let r2 = r1.dup_aligned(0, r1.size(), r1.size()).unwrap();
+        assert_eq!(unsafe { *r2.base() }, 1);
+        unsafe { *r2.base() = 2 };
+        assert_eq!(unsafe { *r1.base() }, 1);
+
+        r2.free().unwrap();
+        r1.free().unwrap();
+    }
+}
@@ -121,7 +121,7 @@ impl MachVMRegion {
 
 impl VirtualRegion for MachVMRegion {
     fn new(size: usize, align: usize) -> Result<MachVMRegion, Error> {
-        debug_assert!(size.is_power_of_two());
+        debug_assert!(0 == size % super::page_size());
         debug_assert!(align.is_power_of_two());
 
         let (addr, _) = unsafe { Self::_allocate(size, None, align)? };
@@ -129,7 +129,8 @@ impl VirtualRegion for MachVMRegion {
     }
 
     unsafe fn from_raw_parts(addr: *mut u8, size: usize) -> MachVMRegion {
-        debug_assert!(size.is_power_of_two());
+        // whole pages: guarded blocks' bodies are a page short of a power of two
+        debug_assert!(0 == size % super::page_size());
 
         MachVMRegion { begin: addr, size }
     }
@@ -163,6 +164,49 @@ impl VirtualRegion for MachVMRegion {
         Ok(unsafe { MachVMRegion::from_raw_parts(addr, size) })
     }
 
+    fn extend(&mut self, size: usize) -> Result<(), Error> {
+        debug_assert!(size > self.size);
+        let mut addr = unsafe { self.begin.add(self.size) } as vm_types::mach_vm_address_t;
+        // without VM_FLAGS_OVERWRITE, this fails if anything is mapped there
+        let kr = unsafe {
+            vm::mach_vm_allocate(
+                traps::mach_task_self(),
+                &mut addr as *mut vm_types::mach_vm_address_t,
+                (size - self.size) as vm_types::mach_vm_size_t,
+                vm_statistics::VM_FLAGS_FIXED,
+            )
+        };
+        match kr {
+            KERN_SUCCESS => {
+                self.size = size;
+                Ok(())
+            },
+            KERN_NO_SPACE => Err(Error::OutOfMemory),
+            _ => Err(Error::Generic(mach_error_to_string(kr))),
+        }
+    }
+
+    fn remap(&mut self, size: usize, align: usize) -> Result<(), Error> {
+        debug_assert!(size > self.size);
+        let (addr, _) = unsafe { Self::_allocate(size, None, align)? };
+        // share the pages with the new region, then drop the old one's view
+        if let Err(e) = unsafe { Self::_remap(self.begin, self.size, false, Some(addr), 0) } {
+            unsafe { Self::_deallocate(addr, size)? };
+            return Err(e)
+        }
+        unsafe { Self::_deallocate(self.begin, self.size)? };
+        self.begin = addr;
+        self.size = size;
+        Ok(())
+    }
+
+    fn shrink(&mut self, size: usize) -> Result<(), Error> {
+        debug_assert!(size < self.size);
+        unsafe { Self::_deallocate(self.begin.add(size), self.size - size)? };
+        self.size = size;
+        Ok(())
+    }
+
     fn detach(&mut self) -> Result<(), Error> {
         let (addr, _) = unsafe { Self::_allocate(self.size, Some(self.begin), 0)? };
         if addr != self.begin {
@@ -171,6 +215,18 @@ impl VirtualRegion for MachVMRegion {
         Ok(())
     }
 
+    /// Reusable pages no longer count towards the task's footprint; what they
+    /// read back as is undefined.
+    fn purge(&mut self) -> Result<bool, Error> {
+        let r = unsafe {
+            libc::madvise(self.begin as *mut libc::c_void, self.size, libc::MADV_FREE_REUSABLE)
+        };
+        match r {
+            0 => Ok(false),
+            _ => Err(Error::Generic(std::io::Error::last_os_error().to_string())),
+        }
+    }
+
     fn prot(&mut self, read: bool, write: bool) -> Result<(bool, bool), Error> {
         let flags = match (read, write) {
             (true, true) => vm_prot::VM_PROT_READ | vm_prot::VM_PROT_WRITE,
@@ -13,7 +13,21 @@ pub trait VirtualRegion: Sized {
     fn dup_to(&self, offset: usize, size: usize, target: *mut u8) -> Result<Self, Error>;
     fn dup_aligned(&self, offset: usize, size: usize, target_align: usize) -> Result<Self, Error>;
 
+    /// Grow the region in place to `size` bytes, onto the address space right
+    /// after it; fails if any of that is taken. The new pages read as zeroes.
+    fn extend(&mut self, size: usize) -> Result<(), Error>;
+    /// Move the region to fresh address space of `size` bytes aligned to
+    /// `align`, taking its pages along instead of copying them. Past the old
+    /// size, the new pages read as zeroes.
+    fn remap(&mut self, size: usize, align: usize) -> Result<(), Error>;
+    /// Cut the region down to `size` bytes, giving the pages past that back.
+    fn shrink(&mut self, size: usize) -> Result<(), Error>;
+
     fn detach(&mut self) -> Result<(), Error>;
+    /// Let the OS take back the region's physical pages, keeping the address
+    /// range mapped. Returns whether the pages read back as zeroes afterwards;
+    /// otherwise their contents are undefined.
+    fn purge(&mut self) -> Result<bool, Error>;
 
     fn prot(&mut self, read: bool, write: bool) -> Result<(bool, bool), Error>;
 
@@ -27,6 +41,18 @@ pub mod macos;
 #[cfg(target_os = "macos")]
 pub type VMRegion = macos::MachVMRegion;
 
+#[cfg(target_os = "linux")]
+pub mod linux;
+
+#[cfg(target_os = "linux")]
+pub type VMRegion = linux::LinuxVMRegion;
+
+#[cfg(all(unix, not(any(target_os = "macos", target_os = "linux"))))]
+pub mod posix;
+
+#[cfg(all(unix, not(any(target_os = "macos", target_os = "linux"))))]
+pub type VMRegion = posix::PosixVMRegion;
+
 use page_size as extern_page_size;
 use parking_lot::Once;
 
@@ -0,0 +1,255 @@
+use std::io;
+use std::ptr;
+
+use super::VirtualRegion;
+use crate::Error;
+
+/// Plain anonymous `mmap` regions, for Unix targets without a specialised
+/// backend. Without shared file backing there is no way to alias physical
+/// pages, so `map_to`/`map_aligned` and `remap` report `Error::Unsupported`;
+/// duplicates are made by copying.
+#[repr(C)]
+pub struct PosixVMRegion {
+    begin: *mut u8,
+    size: usize,
+}
+
+fn last_error() -> Error {
+    let err = io::Error::last_os_error();
+    match err.raw_os_error() {
+        Some(libc::ENOMEM) => Error::OutOfMemory,
+        _ => Error::Generic(err.to_string()),
+    }
+}
+
+impl PosixVMRegion {
+    unsafe fn _allocate(size: usize, target: Option<*mut u8>) -> Result<*mut u8, Error> {
+        let flags = match target {
+            None => libc::MAP_PRIVATE | libc::MAP_ANON,
+            Some(_) => libc::MAP_PRIVATE | libc::MAP_ANON | libc::MAP_FIXED,
+        };
+        let addr = libc::mmap(
+            target.unwrap_or(ptr::null_mut()) as *mut libc::c_void,
+            size,
+            libc::PROT_READ | libc::PROT_WRITE,
+            flags,
+            -1,
+            0,
+        );
+        if addr == libc::MAP_FAILED {
+            return Err(last_error())
+        }
+        Ok(addr as *mut u8)
+    }
+
+    unsafe fn _deallocate(begin: *mut u8, size: usize) -> Result<(), Error> {
+        match libc::munmap(begin as *mut libc::c_void, size) {
+            0 => Ok(()),
+            _ => Err(last_error()),
+        }
+    }
+
+    /// Over-allocate by `align` and trim both ends.
+    unsafe fn _allocate_aligned(size: usize, align: usize) -> Result<*mut u8, Error> {
+        if align <= super::page_size() {
+            return Self::_allocate(size, None)
+        }
+        let raw = Self::_allocate(size + align, None)? as usize;
+        let aligned = (raw + align - 1) & !(align - 1);
+        if aligned > raw {
+            Self::_deallocate(raw as *mut u8, aligned - raw)?;
+        }
+        let tail = raw + size + align - (aligned + size);
+        if tail > 0 {
+            Self::_deallocate((aligned + size) as *mut u8, tail)?;
+        }
+        Ok(aligned as *mut u8)
+    }
+
+    unsafe fn _copy_into(&self, offset: usize, size: usize, target: *mut u8) {
+        ptr::copy_nonoverlapping(self.begin.add(offset), target, size);
+    }
+}
+
+impl VirtualRegion for PosixVMRegion {
+    fn new(size: usize, align: usize) -> Result<PosixVMRegion, Error> {
+        debug_assert!(0 == size % super::page_size());
+        debug_assert!(align.is_power_of_two());
+
+        let addr = unsafe { Self::_allocate_aligned(size, align)? };
+        Ok(PosixVMRegion { begin: addr, size })
+    }
+
+    unsafe fn from_raw_parts(addr: *mut u8, size: usize) -> PosixVMRegion {
+        // whole pages: guarded blocks' bodies are a page short of a power of two
+        debug_assert!(0 == size % super::page_size());
+
+        PosixVMRegion { begin: addr, size }
+    }
+
+    fn base(&self) -> *mut u8 { self.begin }
+    fn size(&self) -> usize { self.size }
+
+    fn map_to(&self, _offset: usize, _size: usize, _target: *mut u8) -> Result<Self, Error> {
+        Err(Error::Unsupported)
+    }
+    fn map_aligned(&self, _offset: usize, _size: usize, _align: usize) -> Result<Self, Error> {
+        Err(Error::Unsupported)
+    }
+
+    fn dup_to(&self, offset: usize, size: usize, target: *mut u8) -> Result<Self, Error> {
+        // mapping over `target` would drop whatever it overlays, so it mustn't
+        // overlay this region: the copy goes straight from one to the other
+        debug_assert!(
+            target as usize >= self.begin as usize + self.size
+                || target as usize + size <= self.begin as usize
+        );
+        let addr = unsafe { Self::_allocate(size, Some(target))? };
+        unsafe { self._copy_into(offset, size, addr) };
+        Ok(PosixVMRegion { begin: addr, size })
+    }
+    fn dup_aligned(&self, offset: usize, size: usize, target_align: usize) -> Result<Self, Error> {
+        let addr = unsafe { Self::_allocate_aligned(size, target_align)? };
+        unsafe { self._copy_into(offset, size, addr) };
+        Ok(PosixVMRegion { begin: addr, size })
+    }
+
+    /// `MAP_FIXED` would replace whatever is mapped there, so the address is
+    /// only a hint, and a mapping that lands elsewhere is given back.
+    fn extend(&mut self, size: usize) -> Result<(), Error> {
+        debug_assert!(size > self.size);
+        let tail = unsafe { self.begin.add(self.size) };
+        let addr = unsafe {
+            libc::mmap(
+                tail as *mut libc::c_void,
+                size - self.size,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_PRIVATE | libc::MAP_ANON,
+                -1,
+                0,
+            )
+        };
+        if addr == libc::MAP_FAILED {
+            return Err(last_error())
+        }
+        if addr as *mut u8 != tail {
+            unsafe { Self::_deallocate(addr as *mut u8, size - self.size)? };
+            return Err(Error::OutOfMemory)
+        }
+        self.size = size;
+        Ok(())
+    }
+
+    fn remap(&mut self, _size: usize, _align: usize) -> Result<(), Error> {
+        Err(Error::Unsupported)
+    }
+
+    fn shrink(&mut self, size: usize) -> Result<(), Error> {
+        debug_assert!(size < self.size);
+        unsafe { Self::_deallocate(self.begin.add(size), self.size - size)? };
+        self.size = size;
+        Ok(())
+    }
+
+    fn detach(&mut self) -> Result<(), Error> {
+        let addr = unsafe { Self::_allocate(self.size, Some(self.begin))? };
+        if addr != self.begin {
+            panic!("detach failed: separated address {:#?} (should be {:#?})", addr, self.begin);
+        }
+        Ok(())
+    }
+
+    /// The BSDs don't promise zeroes after `MADV_DONTNEED`.
+    fn purge(&mut self) -> Result<bool, Error> {
+        let r = unsafe {
+            libc::madvise(self.begin as *mut libc::c_void, self.size, libc::MADV_DONTNEED)
+        };
+        match r {
+            0 => Ok(false),
+            _ => Err(last_error()),
+        }
+    }
+
+    fn prot(&mut self, read: bool, write: bool) -> Result<(bool, bool), Error> {
+        let flags = match (read, write) {
+            (true, true) => libc::PROT_READ | libc::PROT_WRITE,
+            (true, false) => libc::PROT_READ,
+            (false, true) => libc::PROT_WRITE,
+            (false, false) => libc::PROT_NONE,
+        };
+        match unsafe { libc::mprotect(self.begin as *mut libc::c_void, self.size, flags) } {
+            0 => Ok((read, write)),
+            _ => Err(last_error()),
+        }
+    }
+
+    fn consume(self) -> (*mut u8, usize) { (self.begin, self.size) }
+
+    fn free(self) -> Result<(), Error> { unsafe { Self::_deallocate(self.begin, self.size) } }
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::VirtualRegion;
+    use super::PosixVMRegion;
+    use crate::Error;
+
+    const TEST_SIZE: usize = 4 * crate::constants::MB;
+
+    #[test]
+    fn test_alloc_free() {
+        let r = PosixVMRegion::new(TEST_SIZE, TEST_SIZE).unwrap();
+        assert_eq!(r.base() as usize % TEST_SIZE, 0);
+        unsafe {
+            for i in 0..(TEST_SIZE / 0x1000) {
+                *r.base().offset(i as isize * 0x1000) = 1;
+            }
+        }
+        r.free().unwrap();
+    }
+
+    #[test]
+    fn test_map_unsupported() {
+        let mut r1 = PosixVMRegion::new(0x4000usize, 0x4000usize).unwrap();
+        let r2 = PosixVMRegion::new(0x4000usize, 0x4000usize).unwrap();
+        assert!(matches!(r1.map_aligned(0, r1.size(), r1.size()), Err(Error::Unsupported)));
+        assert!(matches!(r1.map_to(0, r1.size(), r2.base()), Err(Error::Unsupported)));
+        assert!(matches!(r1.remap(0x8000, 0x4000), Err(Error::Unsupported)));
+        r1.free().unwrap();
+        r2.free().unwrap();
+    }
+
+    #[test]
+    fn test_extend_shrink() {
+        let mut r1 = PosixVMRegion::new(0x8000usize, 0x4000usize).unwrap();
+        unsafe { *r1.base() = 1 };
+        let begin = r1.base();
+        r1.shrink(0x4000).unwrap();
+        // the hint is taken if the pages just given back are still free
+        if r1.extend(0x8000).is_ok() {
+            assert_eq!((r1.base(), r1.size()), (begin, 0x8000));
+            unsafe { *r1.base().add(0x7fff) = 2 };
+        }
+        assert_eq!(unsafe { *r1.base() }, 1);
+        r1.free().unwrap();
+    }
+
+    #[test]
+    fn test_dup_detach() {
+        let r1 = PosixVMRegion::new(0x4000usize, 0x4000usize).unwrap();
+        unsafe { *r1.base() = 1 };
+        let mut r2 = PosixVMRegion::new(0x4000usize, 0x4000usize).unwrap();
+
+        let r3 = r1.dup_to(0, r1.size(), r2.base()).unwrap();
+        assert_eq!(r3.base(), r2.base());
+        assert_eq!(unsafe { *r2.base() }, 1);
+        unsafe { *r2.base() = 2 };
+        assert_eq!(unsafe { *r1.base() }, 1);
+
+        r2.detach().unwrap();
+        assert_eq!(unsafe { *r2.base() }, 0);
+
+        r1.free().unwrap();
+        r2.free().unwrap();
+    }
+}
@@ -0,0 +1,86 @@
+#![cfg(all(feature = "canary", unix))]
+
+extern crate aura;
+
+mod common;
+
+use std::alloc::{GlobalAlloc, Layout};
+use std::slice;
+
+use aura::{
+    aura_alloc, aura_calloc, aura_free, aura_realloc, aura_usable_size, Aura, CANARY, CANARY_BYTES,
+};
+use common::{assert_killed_by, in_child, run_in_child};
+
+#[test]
+fn objects_are_followed_by_a_canary() {
+    // 1000 has room for the canary in its size class; 100 and 4096 don't, and
+    // get a bigger one
+    for &size in &[1000, 100, 4096] {
+        let obj = aura_alloc(size);
+        let usable = aura_usable_size(obj);
+        assert!(usable >= size);
+        let canary = unsafe { slice::from_raw_parts(obj.add(usable), CANARY_BYTES) };
+        assert!(canary.iter().all(|&byte| byte == CANARY), "no canary after {} bytes", size);
+        unsafe { obj.write_bytes(0x5a, usable) };
+        aura_free(obj);
+    }
+}
+
+#[test]
+fn growing_in_place_keeps_the_canary_clear() {
+    let obj = aura_alloc(100);
+    let grown = aura_realloc(obj, 104);
+    unsafe { grown.write_bytes(0x5a, 104) };
+    aura_free(grown);
+}
+
+/// Zero and free small objects from fresh slots, whose canary the free list
+/// link must not be left in.
+fn zero_small_objects() {
+    let objs = (0..1024).map(|_| aura_calloc(1, 1)).collect::<Vec<_>>();
+    objs.into_iter().for_each(aura_free);
+    let layout = Layout::from_size_align(3, 1).unwrap();
+    let objs = (0..1024).map(|_| unsafe { Aura.alloc_zeroed(layout) }).collect::<Vec<_>>();
+    objs.into_iter().for_each(|obj| unsafe { Aura.dealloc(obj, layout) });
+}
+
+#[test]
+fn zeroing_small_objects_keeps_the_canary() {
+    if in_child() {
+        return zero_small_objects()
+    }
+    // other tests leave empty blocks behind, which aren't fresh once reused
+    let child = run_in_child("zeroing_small_objects_keeps_the_canary");
+    assert!(
+        child.status.success(),
+        "child exited with {}: {}",
+        child.status,
+        String::from_utf8_lossy(&child.stderr)
+    );
+}
+
+/// Write one byte past the usable end of an object, and free it.
+fn off_by_one() {
+    let obj = aura_alloc(100);
+    unsafe { obj.write_bytes(0, aura_usable_size(obj) + 1) };
+    aura_free(obj);
+}
+
+#[test]
+fn overflows_abort_on_free() {
+    if in_child() {
+        return off_by_one()
+    }
+    let child = run_in_child("overflows_abort_on_free");
+    assert_killed_by(&child, &[libc::SIGABRT]);
+    let obj = aura_alloc(100);
+    let usable = aura_usable_size(obj);
+    aura_free(obj);
+    let stderr = String::from_utf8_lossy(&child.stderr);
+    assert!(
+        stderr.contains("write past the end") && stderr.contains(&format!("+{}", usable)),
+        "child said: {}",
+        stderr
+    );
+}
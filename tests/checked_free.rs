@@ -0,0 +1,55 @@
+extern crate aura;
+
+mod common;
+
+use aura::{aura_alloc, aura_alloc_aligned, aura_try_free, Error};
+use common::SEGMENT_SIZE;
+
+#[test]
+fn double_frees_are_caught() {
+    let obj = aura_alloc(48);
+    let other = aura_alloc(48);
+    assert!(aura_try_free(obj).is_ok());
+    assert!(matches!(aura_try_free(obj), Err(Error::DoubleFree(o)) if o == obj as usize));
+
+    // the slot is handed out again once the rest of the block is used up, and
+    // can be freed once more
+    let mut objs = vec![other];
+    while *objs.last().unwrap() != obj {
+        assert!(objs.len() < 64 * 1024 / 48, "the freed slot wasn't handed out again");
+        objs.push(aura_alloc(48));
+    }
+    objs.into_iter().for_each(|o| assert!(aura_try_free(o).is_ok()));
+}
+
+#[test]
+fn foreign_pointers_are_caught() {
+    let mut local = 0u64;
+    let on_stack = &mut local as *mut u64 as *mut u8;
+    assert!(matches!(aura_try_free(on_stack), Err(Error::InvalidFree(_))));
+    let boxed = Box::into_raw(Box::new(0u64));
+    assert!(matches!(aura_try_free(boxed as *mut u8), Err(Error::InvalidFree(_))));
+    drop(unsafe { Box::from_raw(boxed) });
+    assert!(aura_try_free(std::ptr::null_mut()).is_ok());
+}
+
+#[test]
+fn pointers_into_objects_are_caught() {
+    let obj = aura_alloc(48);
+    let inside = unsafe { obj.add(8) };
+    assert!(matches!(aura_try_free(inside), Err(Error::InvalidFree(o)) if o == inside as usize));
+    // the segment's headers
+    let headers = (obj as usize & !(SEGMENT_SIZE - 1)) + 64;
+    assert!(matches!(aura_try_free(headers as *mut u8), Err(Error::InvalidFree(_))));
+    // nothing was freed along the way
+    assert!(aura_try_free(obj).is_ok());
+}
+
+#[test]
+fn dedicated_segments_are_checked() {
+    let obj = aura_alloc_aligned(8 * 1024 * 1024, 8);
+    assert!(matches!(aura_try_free(unsafe { obj.add(8) }), Err(Error::InvalidFree(_))));
+    assert!(aura_try_free(obj).is_ok());
+    // its segment is gone
+    assert!(matches!(aura_try_free(obj), Err(Error::InvalidFree(_))));
+}
@@ -0,0 +1,72 @@
+//! Helpers shared by the integration tests; each test binary takes what it
+//! needs with `mod common;`.
+#![allow(dead_code)]
+
+use std::env;
+#[cfg(unix)]
+use std::os::unix::process::ExitStatusExt;
+use std::process::{Command, Output};
+
+use aura::{aura_alloc, aura_free};
+
+pub const BLOCK_SIZE: usize = 64 * 1024;
+pub const SEGMENT_SIZE: usize = 4 * 1024 * 1024;
+
+const CHILD: &str = "AURA_TEST_CHILD";
+
+/// Resident memory of the whole process. A test that measures it needs a
+/// test binary of its own: RSS has to be measured with nothing else running.
+#[cfg(target_os = "linux")]
+pub fn resident_bytes() -> usize {
+    let statm = std::fs::read_to_string("/proc/self/statm").unwrap();
+    let pages: usize = statm.split_whitespace().nth(1).unwrap().parse().unwrap();
+    pages * page_size()
+}
+
+#[cfg(unix)]
+pub fn page_size() -> usize { unsafe { libc::sysconf(libc::_SC_PAGESIZE) as usize } }
+
+/// Allocate `blocks` blocks' worth of 3000 byte objects and touch them all.
+pub fn fill(blocks: usize) -> Vec<*mut u8> {
+    let objs = (0..blocks * 21).map(|_| aura_alloc(3000)).collect::<Vec<_>>();
+    for &obj in &objs {
+        unsafe { std::ptr::write_bytes(obj, 0xa5, 3000) };
+    }
+    objs
+}
+
+/// Free everything, then refill the active block so that the bucket hands the
+/// empties back.
+pub fn empty(objs: Vec<*mut u8>) {
+    objs.into_iter().for_each(aura_free);
+    let objs = (0..22).map(|_| aura_alloc(3000)).collect::<Vec<_>>();
+    objs.into_iter().for_each(aura_free);
+}
+
+/// Hand `blocks` blocks that have been written to back to the top-level empty.
+pub fn empty_blocks(blocks: usize) { empty(fill(blocks)) }
+
+/// Whether this is a child started by `run_in_child`.
+pub fn in_child() -> bool { env::var_os(CHILD).is_some() }
+
+/// Run the test named `name` again in a child process, where `in_child` holds,
+/// and wait for it. Aborting or faulting takes the whole process, so a test
+/// that expects to do either has it happen in a child.
+pub fn run_in_child(name: &str) -> Output {
+    Command::new(env::current_exe().unwrap())
+        .args(&["--exact", name, "--nocapture"])
+        .env(CHILD, "1")
+        .output()
+        .unwrap()
+}
+
+/// Check that a child from `run_in_child` was killed by one of `signals`.
+#[cfg(unix)]
+pub fn assert_killed_by(child: &Output, signals: &[i32]) {
+    assert!(
+        child.status.signal().map_or(false, |signal| signals.contains(&signal)),
+        "child exited with {}: {}",
+        child.status,
+        String::from_utf8_lossy(&child.stderr)
+    );
+}
@@ -0,0 +1,61 @@
+#![cfg(all(feature = "debug-uaf", unix))]
+
+extern crate aura;
+
+mod common;
+
+use std::slice;
+
+use aura::{
+    aura_alloc, aura_free, aura_set_quarantine_bytes, aura_try_free, aura_usable_size, POISON,
+};
+use common::{assert_killed_by, in_child, run_in_child, BLOCK_SIZE};
+
+#[test]
+fn freed_objects_are_poisoned_and_held_back() {
+    let obj = aura_alloc(200);
+    let size = aura_usable_size(obj);
+    let per_block = BLOCK_SIZE / size;
+    unsafe { obj.write_bytes(0x5a, size) };
+    aura_free(obj);
+    assert!(unsafe { slice::from_raw_parts(obj, size) }.iter().all(|&byte| byte == POISON));
+
+    // without the quarantine, the slot would come around again within a block
+    let objs = (0..2 * per_block).map(|_| aura_alloc(200)).collect::<Vec<_>>();
+    assert!(!objs.contains(&obj));
+    objs.into_iter().for_each(aura_free);
+}
+
+/// Write to an object that went straight back to its block, and allocate
+/// until its slot is handed out again.
+fn write_after_free() {
+    aura_set_quarantine_bytes(0);
+    let obj = aura_alloc(200);
+    let per_block = BLOCK_SIZE / aura_usable_size(obj);
+    aura_free(obj);
+    unsafe { *obj.add(100) = 0 };
+    let reused = (0..per_block).map(|_| aura_alloc(200)).any(|other| other == obj);
+    assert!(!reused, "handed out an object written to after it was freed");
+}
+
+#[test]
+fn writes_after_free_abort() {
+    if in_child() {
+        return write_after_free()
+    }
+    let child = run_in_child("writes_after_free_abort");
+    assert_killed_by(&child, &[libc::SIGABRT]);
+    let stderr = String::from_utf8_lossy(&child.stderr);
+    assert!(stderr.contains("write after free"), "child said: {}", stderr);
+}
+
+#[test]
+fn objects_freed_around_the_quarantine_are_poisoned() {
+    let obj = aura_alloc(200);
+    let size = aura_usable_size(obj);
+    unsafe { obj.write_bytes(0x5a, size) };
+    assert!(aura_try_free(obj).is_ok());
+    // past the free list link
+    let body = unsafe { slice::from_raw_parts(obj.add(8), size - 8) };
+    assert!(body.iter().all(|&byte| byte == POISON));
+}
@@ -0,0 +1,37 @@
+extern crate aura;
+
+mod common;
+
+use std::collections::HashSet;
+use std::thread;
+
+use aura::{aura_alloc, aura_free, aura_usable_size};
+use common::BLOCK_SIZE;
+
+// its own test binary: no other thread's blocks around to adopt
+#[test]
+fn remote_frees_are_reused_by_owner() {
+    let first = aura_alloc(3000);
+    let per_block = BLOCK_SIZE / aura_usable_size(first);
+    // fill three blocks: the first two are inactive and full afterwards, and
+    // so is the active one
+    let mut objs = vec![first as usize];
+    objs.extend((1..3 * per_block).map(|_| aura_alloc(3000) as usize));
+    let block = first as usize & !(BLOCK_SIZE - 1);
+    assert!(objs[..per_block].iter().all(|&obj| obj & !(BLOCK_SIZE - 1) == block));
+
+    // another thread frees all but one object of the first block
+    let freed = objs[1..per_block].to_vec();
+    let remote = freed.clone();
+    thread::spawn(move || remote.into_iter().for_each(|obj| aura_free(obj as *mut u8)))
+        .join()
+        .unwrap();
+
+    // the owner allocates from those again instead of taking a new block
+    let reused = (1..per_block).map(|_| aura_alloc(3000) as usize).collect::<HashSet<_>>();
+    assert_eq!(reused, freed.iter().copied().collect::<HashSet<_>>());
+
+    reused.into_iter().for_each(|obj| aura_free(obj as *mut u8));
+    aura_free(first);
+    objs[per_block..].iter().for_each(|&obj| aura_free(obj as *mut u8));
+}
@@ -0,0 +1,43 @@
+#![cfg(target_os = "linux")]
+
+extern crate aura;
+
+use aura::{aura_alloc, aura_free};
+
+const MB: usize = 1024 * 1024;
+
+fn filled(obj: *mut u8, size: usize, byte: u8) -> bool {
+    (0..size).all(|i| unsafe { *obj.add(i) } == byte)
+}
+
+#[test]
+fn forked_child_has_a_heap_of_its_own() {
+    let (small, huge) = (aura_alloc(3000), aura_alloc(8 * MB));
+    unsafe {
+        small.write_bytes(1, 3000);
+        huge.write_bytes(1, 8 * MB);
+    }
+
+    let pid = unsafe { libc::fork() };
+    if pid == 0 {
+        // it starts out with the parent's data, then writes all over it
+        let copied = filled(small, 3000, 1) && filled(huge, 8 * MB, 1);
+        unsafe {
+            small.write_bytes(2, 3000);
+            huge.write_bytes(2, 8 * MB);
+        }
+        aura_free(small);
+        let objs = (0..64).map(|_| aura_alloc(3000)).collect::<Vec<_>>();
+        objs.iter().for_each(|&obj| unsafe { obj.write_bytes(3, 3000) });
+        let rewritten = filled(huge, 8 * MB, 2);
+        unsafe { libc::_exit(if copied && rewritten { 0 } else { 1 }) };
+    }
+
+    let mut status = 0;
+    assert_eq!(unsafe { libc::waitpid(pid, &mut status, 0) }, pid);
+    assert!(libc::WIFEXITED(status) && 0 == libc::WEXITSTATUS(status), "child: {:#x}", status);
+    assert!(filled(small, 3000, 1));
+    assert!(filled(huge, 8 * MB, 1));
+    aura_free(small);
+    aura_free(huge);
+}
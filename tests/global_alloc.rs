@@ -0,0 +1,178 @@
+//! Runs the stress patterns from the unit tests with aura installed as the
+//! global allocator, so that every `Box` and `Vec` goes through it.
+//!
+//! `harness = false`: libtest's output capturing allocates from inside
+//! `print!`, which isn't something to lean on while testing the allocator.
+
+extern crate crossbeam_channel;
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+
+use aura::constants::KB;
+use aura::Aura;
+use rand::prelude::*;
+
+#[global_allocator]
+static GLOBAL: Aura = Aura;
+
+fn stress_test_masd() {
+    print!("Testing many allocator threads, one deallocator thread...");
+
+    let (tx, rx) = crossbeam_channel::unbounded::<Box<[u8; 16]>>();
+    let mut handles = Vec::new();
+    for i in 0..4 {
+        let tx = tx.clone();
+        handles.push(thread::spawn(move || {
+            for _ in 0..9 {
+                tx.send(Box::new([i as u8; 16])).unwrap();
+            }
+        }));
+    }
+    drop(tx);
+    for handle in handles.into_iter() {
+        handle.join().unwrap();
+    }
+    for alloc in rx.iter() {
+        assert!(alloc.iter().all(|&b| b == alloc[0]));
+    }
+    println!("ok");
+}
+
+fn stress_test_samd() {
+    print!("Testing one allocator thread, many deallocator threads...");
+
+    let mut allocs = Vec::new();
+    for i in 0..36 {
+        allocs.push(vec![i as u8; 8 * KB - 1]);
+    }
+    let mut handles = Vec::new();
+    for object in allocs.drain(..) {
+        handles.push(thread::spawn(move || {
+            assert!(object.iter().all(|&b| b == object[0]));
+            drop(object);
+        }));
+    }
+    for handle in handles.into_iter() {
+        handle.join().unwrap();
+    }
+    for i in 0..36 {
+        allocs.push(vec![i as u8; 8 * KB - 1]);
+    }
+    for (i, object) in allocs.iter().enumerate() {
+        assert!(object.iter().all(|&b| b == i as u8));
+    }
+    println!("ok");
+}
+
+fn stress_test_alignment() {
+    print!("Testing aligned layouts...");
+
+    #[repr(align(64))]
+    struct Aligned64([u8; 48]);
+    #[repr(align(4096))]
+    struct Aligned4K([u8; 100]);
+
+    let small = (0..256).map(|_| Box::new(Aligned64([0; 48]))).collect::<Vec<_>>();
+    let pages = (0..16).map(|_| Box::new(Aligned4K([0; 100]))).collect::<Vec<_>>();
+    assert!(small.iter().all(|b| 0 == &**b as *const Aligned64 as usize % 64));
+    assert!(pages.iter().all(|b| 0 == &**b as *const Aligned4K as usize % 4096));
+    println!("ok");
+}
+
+fn stress_test_vec_growth() {
+    print!("Testing Vec growth across size classes...");
+
+    let mut v = Vec::new();
+    for i in 0..(64 * KB) as u64 {
+        v.push(i);
+    }
+    assert!(v.iter().enumerate().all(|(i, &x)| i as u64 == x));
+    v.truncate(100);
+    v.shrink_to_fit();
+    assert!(v.iter().enumerate().all(|(i, &x)| i as u64 == x));
+    println!("ok");
+}
+
+fn stress_test_mamd() {
+    print!("Testing many allocator threads, many deallocator threads...");
+
+    let iterations_per_thread = 20000usize;
+    let num_allocated = Arc::new(AtomicUsize::new(0));
+    let num_freed = Arc::new(AtomicUsize::new(0));
+
+    let num_threads = num_cpus::get();
+    let mut receivers = Vec::new();
+    let mut senders = Vec::new();
+    for _ in 0..num_threads {
+        let (tx, rx) = crossbeam_channel::unbounded::<Vec<u8>>();
+        receivers.push(rx);
+        senders.push(tx);
+    }
+
+    let mut handles = Vec::new();
+    for _ in 0..num_threads {
+        let num_allocated = Arc::clone(&num_allocated);
+        let num_freed = Arc::clone(&num_freed);
+        let thread_rx = receivers.pop().unwrap();
+        let thread_tx_bank = senders.iter().map(|tx| tx.clone()).collect::<Vec<_>>();
+        handles.push(thread::spawn(move || {
+            let mut objects = Vec::<Vec<u8>>::new();
+            for _ in 0..iterations_per_thread {
+                match thread_rng().gen_range(0..4) {
+                    0 => {
+                        let size = thread_rng().gen_range(1..8 * KB);
+                        let fill = size as u8;
+                        objects.push(vec![fill; size]);
+                        num_allocated.fetch_add(1, Ordering::SeqCst);
+                    },
+                    1 => {
+                        if objects.len() > 0 {
+                            let index = thread_rng().gen_range(0..objects.len());
+                            let obj = objects.swap_remove(index);
+                            assert!(obj.iter().all(|&b| b == obj.len() as u8));
+                            num_freed.fetch_add(1, Ordering::SeqCst);
+                        }
+                    },
+                    2 => {
+                        if objects.len() > 0 {
+                            let index = thread_rng().gen_range(0..objects.len());
+                            let obj = objects.swap_remove(index);
+                            let recv_idx = thread_rng().gen_range(0..num_threads);
+                            thread_tx_bank[recv_idx].send(obj).unwrap();
+                        }
+                    },
+                    3 => {
+                        thread_rx.try_iter().for_each(|obj| {
+                            assert!(obj.iter().all(|&b| b == obj.len() as u8));
+                            num_freed.fetch_add(1, Ordering::SeqCst);
+                        });
+                    },
+                    _ => unreachable!(),
+                }
+            }
+            drop(thread_tx_bank);
+            thread_rx.iter().chain(objects.into_iter()).for_each(|obj| {
+                assert!(obj.iter().all(|&b| b == obj.len() as u8));
+                num_freed.fetch_add(1, Ordering::SeqCst);
+            });
+        }));
+    }
+    drop(senders);
+
+    for handle in handles {
+        handle.join().unwrap();
+    }
+
+    assert_eq!(num_allocated.load(Ordering::SeqCst), num_freed.load(Ordering::SeqCst));
+    println!("ok (allocated {})", num_allocated.load(Ordering::Relaxed));
+}
+
+fn main() {
+    stress_test_masd();
+    stress_test_samd();
+    stress_test_alignment();
+    stress_test_vec_growth();
+    stress_test_mamd();
+}
@@ -0,0 +1,55 @@
+#![cfg(all(feature = "hardened", unix))]
+
+extern crate aura;
+
+mod common;
+
+use aura::{aura_alloc, aura_free, aura_set_block_guards, aura_usable_size};
+use common::{assert_killed_by, in_child, page_size, run_in_child, BLOCK_SIZE, SEGMENT_SIZE};
+
+/// Run the test named `name` again in a child, and check that it was killed by
+/// a memory fault.
+fn assert_faults(name: &str) {
+    // Darwin reports some protection faults as SIGBUS
+    assert_killed_by(&run_in_child(name), &[libc::SIGSEGV, libc::SIGBUS]);
+}
+
+#[test]
+fn guarded_blocks_can_be_filled() {
+    aura_set_block_guards(true);
+    let first = aura_alloc(4000);
+    let per_block = (BLOCK_SIZE - page_size()) / aura_usable_size(first);
+    let mut objs = vec![first];
+    objs.extend((1..2 * per_block).map(|_| aura_alloc(4000)));
+    for &obj in &objs {
+        unsafe { std::ptr::write_bytes(obj, 0x5a, aura_usable_size(obj)) };
+    }
+    // the guard page takes a slot: no object in the first block reaches it
+    let block = first as usize & !(BLOCK_SIZE - 1);
+    let in_block = objs.iter().filter(|&&obj| obj as usize & !(BLOCK_SIZE - 1) == block);
+    let end = in_block.map(|&obj| obj as usize + aura_usable_size(obj)).max().unwrap();
+    assert!(end <= block + BLOCK_SIZE - page_size());
+    objs.into_iter().for_each(aura_free);
+}
+
+#[test]
+fn overflowing_a_block_faults() {
+    if !in_child() {
+        return assert_faults("overflowing_a_block_faults")
+    }
+    aura_set_block_guards(true);
+    let obj = aura_alloc(4000);
+    let block = obj as usize & !(BLOCK_SIZE - 1);
+    unsafe { *((block + BLOCK_SIZE - 1) as *mut u8) = 0 };
+}
+
+#[test]
+fn writing_past_the_headers_faults() {
+    if !in_child() {
+        return assert_faults("writing_past_the_headers_faults")
+    }
+    let obj = aura_alloc(4000);
+    let segment = obj as usize & !(SEGMENT_SIZE - 1);
+    // just ahead of the first block's body
+    unsafe { *((segment + BLOCK_SIZE - 1) as *mut u8) = 0 };
+}
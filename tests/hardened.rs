@@ -0,0 +1,35 @@
+#![cfg(all(feature = "hardened", unix))]
+
+extern crate aura;
+
+mod common;
+
+use aura::{aura_alloc, aura_free, aura_usable_size};
+use common::{assert_killed_by, in_child, run_in_child, BLOCK_SIZE};
+
+/// Free two objects of a full block, overwrite the link in the last one freed
+/// as a use-after-free would, and allocate until that link is followed.
+fn hijack_free_list() {
+    // the link has to be in place as the frees reach the block
+    #[cfg(feature = "debug-uaf")]
+    aura::aura_set_quarantine_bytes(0);
+    let first = aura_alloc(3000);
+    let per_block = BLOCK_SIZE / aura_usable_size(first);
+    let mut objs = vec![first];
+    objs.extend((1..per_block).map(|_| aura_alloc(3000)));
+    aura_free(objs[0]);
+    aura_free(objs[1]);
+
+    let mut target = [0u8; 3000];
+    unsafe { *(objs[1] as *mut *mut u8) = target.as_mut_ptr() };
+    let hijacked = (0..2).map(|_| aura_alloc(3000)).any(|obj| obj == target.as_mut_ptr());
+    assert!(!hijacked, "allocated the overwritten link's target");
+}
+
+#[test]
+fn overwritten_links_abort() {
+    if in_child() {
+        return hijack_free_list()
+    }
+    assert_killed_by(&run_in_child("overwritten_links_abort"), &[libc::SIGABRT]);
+}
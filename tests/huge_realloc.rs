@@ -0,0 +1,54 @@
+#![cfg(target_os = "linux")]
+
+extern crate aura;
+
+mod common;
+
+use aura::{aura_alloc, aura_free, aura_realloc, aura_usable_size};
+use common::resident_bytes;
+
+const MB: usize = 1024 * 1024;
+
+fn fill(obj: *mut u8, size: usize) { unsafe { obj.write_bytes(0xa5, size) } }
+
+fn filled(obj: *mut u8, size: usize) -> bool {
+    (0..size).step_by(4096).chain(Some(size - 1)).all(|i| unsafe { *obj.add(i) } == 0xa5)
+}
+
+// one test: where segments land, and RSS, depend on nothing else running
+#[test]
+fn huge_objects_resize_through_the_vm() {
+    // nothing has been mapped after it, so it grows in place
+    let obj = aura_alloc(8 * MB);
+    fill(obj, 8 * MB);
+    assert_eq!(aura_realloc(obj, 16 * MB), obj);
+    assert!(aura_usable_size(obj) >= 16 * MB);
+    assert!(filled(obj, 8 * MB));
+    fill(obj, 16 * MB);
+
+    // shrinking gives the tail back
+    let before = resident_bytes();
+    assert_eq!(aura_realloc(obj, MB), obj);
+    let after = resident_bytes();
+    assert!(aura_usable_size(obj) < 2 * MB);
+    assert!(
+        before.saturating_sub(after) >= 14 * MB,
+        "shrinking only took RSS from {} to {}",
+        before,
+        after
+    );
+    assert!(filled(obj, MB));
+    aura_free(obj);
+
+    // growing over the next segment moves the pages instead
+    let (a, b) = (aura_alloc(8 * MB), aura_alloc(8 * MB));
+    let (low, high) = if a < b { (a, b) } else { (b, a) };
+    fill(low, 8 * MB);
+    let size = high as usize - low as usize + MB;
+    let moved = aura_realloc(low, size);
+    assert_ne!(moved, low);
+    assert!(filled(moved, 8 * MB));
+    fill(moved, size);
+    aura_free(high);
+    aura_free(moved);
+}
@@ -0,0 +1,43 @@
+#![cfg(target_os = "linux")]
+
+extern crate aura;
+
+mod common;
+
+use aura::{aura_alloc, aura_free, aura_mesh};
+use common::{resident_bytes, BLOCK_SIZE};
+
+#[test]
+fn mesh_releases_memory() {
+    let size = 1000;
+    let objs = (0..256 * 64).map(|_| aura_alloc(size)).collect::<Vec<_>>();
+    for (i, &obj) in objs.iter().enumerate() {
+        unsafe { std::ptr::write_bytes(obj, i as u8, size) };
+    }
+    let mut kept = Vec::new();
+    for (i, obj) in objs.into_iter().enumerate() {
+        if i % 17 == 0 {
+            kept.push((i, obj));
+        } else {
+            aura_free(obj);
+        }
+    }
+
+    let before = resident_bytes();
+    let stats = aura_mesh();
+    let after = resident_bytes();
+    let released = stats.meshed;
+    assert!(released >= 32, "only {} blocks meshed: {:?}", released, stats);
+    assert!(
+        before.saturating_sub(after) >= released * BLOCK_SIZE / 2,
+        "meshing {} blocks only shrank RSS from {} to {}",
+        released,
+        before,
+        after
+    );
+
+    for (i, obj) in kept {
+        assert!((0..size).all(|j| unsafe { *obj.add(j) } == i as u8));
+        aura_free(obj);
+    }
+}
@@ -0,0 +1,118 @@
+#![cfg(target_os = "linux")]
+
+extern crate aura;
+extern crate crossbeam_channel;
+
+use std::collections::HashSet;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+
+use aura::{aura_alloc, aura_free, aura_mesh};
+
+const SIZE: usize = 1000;
+const WORDS: usize = SIZE / 8;
+const WRITERS: usize = 4;
+const ROUNDS: usize = 256;
+
+// Each writer owns some objects and keeps rewriting them with a counter,
+// checking every time that its last write is still there.
+fn hammer(objs: crossbeam_channel::Receiver<usize>, stop: Arc<AtomicBool>) -> Vec<(usize, u64)> {
+    let mut owned = Vec::new();
+    while !stop.load(Ordering::Relaxed) {
+        owned.extend(objs.try_iter().map(|obj| (obj, 0u64)));
+        for (obj, counter) in owned.iter_mut() {
+            let words = *obj as *mut u64;
+            for i in 0..WORDS {
+                let seen = unsafe { words.add(i).read_volatile() };
+                assert_eq!(seen, *counter, "lost write to {:#x}", *obj);
+            }
+            *counter += 1;
+            for i in 0..WORDS {
+                unsafe { words.add(i).write_volatile(*counter) };
+            }
+        }
+    }
+    owned
+}
+
+#[test]
+fn mesh_under_concurrent_writes() {
+    let stop = Arc::new(AtomicBool::new(false));
+    let (send, recv) = crossbeam_channel::unbounded();
+    let writers = (0..WRITERS)
+        .map(|_| {
+            let (recv, stop) = (recv.clone(), stop.clone());
+            thread::spawn(move || hammer(recv, stop))
+        })
+        .collect::<Vec<_>>();
+
+    let mut meshed = 0;
+    for _ in 0..ROUNDS {
+        // a few sparse blocks per round, the survivors handed to the writers
+        let objs = (0..8 * 64).map(|_| aura_alloc(SIZE)).collect::<Vec<_>>();
+        for (i, obj) in objs.into_iter().enumerate() {
+            if i % 17 == 0 {
+                unsafe { std::ptr::write_bytes(obj, 0, SIZE) };
+                send.send(obj as usize).unwrap();
+            } else {
+                aura_free(obj);
+            }
+        }
+        meshed += aura_mesh().meshed;
+    }
+    stop.store(true, Ordering::Relaxed);
+    assert!(meshed > 0);
+
+    for writer in writers {
+        for (obj, counter) in writer.join().unwrap() {
+            let words = obj as *mut u64;
+            assert!((0..WORDS).all(|i| unsafe { *words.add(i) } == counter));
+            aura_free(obj as *mut u8);
+        }
+    }
+}
+
+#[test]
+fn mesh_under_concurrent_frees() {
+    let (send, recv) = crossbeam_channel::unbounded::<usize>();
+    let freers = (0..WRITERS)
+        .map(|_| {
+            let recv = recv.clone();
+            thread::spawn(move || recv.iter().for_each(|obj| aura_free(obj as *mut u8)))
+        })
+        .collect::<Vec<_>>();
+
+    let mut meshed = 0;
+    let mut kept = Vec::new();
+    for _ in 0..ROUNDS {
+        // the other threads free half of the survivors while they're meshed
+        let objs = (0..8 * 64).map(|_| aura_alloc(SIZE)).collect::<Vec<_>>();
+        for (i, obj) in objs.into_iter().enumerate() {
+            if i % 34 == 0 {
+                let words = obj as *mut u64;
+                (0..WORDS).for_each(|i| unsafe { *words.add(i) = obj as u64 });
+                kept.push(obj as usize);
+            } else if i % 17 == 0 {
+                send.send(obj as usize).unwrap();
+            } else {
+                aura_free(obj);
+            }
+        }
+        meshed += aura_mesh().meshed;
+    }
+    drop(send);
+    freers.into_iter().for_each(|freer| freer.join().unwrap());
+    assert!(meshed > 0);
+
+    // nothing kept was lost or handed out again
+    let fresh = (0..kept.len() * 16).map(|_| aura_alloc(SIZE) as usize).collect::<HashSet<_>>();
+    assert_eq!(fresh.len(), kept.len() * 16);
+    for &obj in &kept {
+        assert!(!fresh.contains(&obj));
+        let words = obj as *mut u64;
+        assert!((0..WORDS).all(|i| unsafe { *words.add(i) } == obj as u64));
+        aura_free(obj as *mut u8);
+    }
+    fresh.into_iter().for_each(|obj| aura_free(obj as *mut u8));
+}
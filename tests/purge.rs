@@ -0,0 +1,43 @@
+#![cfg(target_os = "linux")]
+
+extern crate aura;
+
+mod common;
+
+use std::time::Duration;
+
+use aura::{aura_calloc, aura_free, aura_purge, aura_set_purge_delay, aura_set_segment_cache};
+use common::{empty_blocks, resident_bytes, BLOCK_SIZE};
+
+#[test]
+fn purge_releases_memory() {
+    // no purging behind the test's back
+    aura_set_purge_delay(Duration::from_secs(3600));
+    // nor segments unmapped: empties have to stay to be purged
+    aura_set_segment_cache(usize::MAX);
+    empty_blocks(256);
+    let before = resident_bytes();
+    let purged = aura_purge();
+    let after = resident_bytes();
+    assert!(purged >= 200, "only {} blocks purged", purged);
+    assert!(
+        before.saturating_sub(after) >= purged * BLOCK_SIZE / 2,
+        "purging {} blocks only shrank RSS from {} to {}",
+        purged,
+        before,
+        after
+    );
+    assert_eq!(aura_purge(), 0);
+
+    // purged blocks come back zeroed
+    let objs = (0..256 * 21).map(|_| aura_calloc(1, 3000)).collect::<Vec<_>>();
+    for obj in objs {
+        assert!((0..3000).all(|i| unsafe { *obj.add(i) } == 0));
+        aura_free(obj);
+    }
+
+    // with no delay, empties are purged as they come in
+    aura_set_purge_delay(Duration::from_secs(0));
+    empty_blocks(64);
+    assert_eq!(aura_purge(), 0);
+}
@@ -0,0 +1,71 @@
+#![cfg(target_os = "linux")]
+
+extern crate aura;
+
+use std::thread;
+use std::time::{Duration, Instant};
+
+use aura::{
+    aura_alloc, aura_free, aura_scavenger_pause, aura_scavenger_resume, aura_scavenger_start,
+    aura_scavenger_stats, aura_scavenger_stop, ScavengerConfig, ScavengerStats,
+};
+
+fn wait_for(what: &str, done: impl Fn(&ScavengerStats) -> bool) -> ScavengerStats {
+    let deadline = Instant::now() + Duration::from_secs(10);
+    loop {
+        let stats = aura_scavenger_stats();
+        if done(&stats) {
+            return stats
+        }
+        assert!(Instant::now() < deadline, "gave up waiting for {}: {:?}", what, stats);
+        thread::sleep(Duration::from_millis(10));
+    }
+}
+
+// its own test binary: there's one scavenger per process, and it would mesh
+// the blocks of the other tests' threads
+#[test]
+fn scavenger_meshes_and_releases() {
+    let size = 1000;
+    let objs = (0..64 * 64).map(|_| aura_alloc(size)).collect::<Vec<_>>();
+    let mut kept = Vec::new();
+    for (i, obj) in objs.into_iter().enumerate() {
+        if i % 17 == 0 {
+            unsafe { std::ptr::write_bytes(obj, i as u8, size) };
+            kept.push((i, obj));
+        } else {
+            aura_free(obj);
+        }
+    }
+    // empty blocks: free a batch, then refill the active block so that the
+    // bucket hands the empties back to the top-level
+    let objs = (0..16 * 64).map(|_| aura_alloc(3000)).collect::<Vec<_>>();
+    objs.into_iter().for_each(aura_free);
+    let objs = (0..64).map(|_| aura_alloc(3000)).collect::<Vec<_>>();
+    objs.into_iter().for_each(aura_free);
+
+    assert!(aura_scavenger_start(ScavengerConfig {
+        interval: Duration::from_millis(5),
+        meshes_per_sec: 10_000,
+        cpu_budget: 0.5,
+    }));
+    assert!(!aura_scavenger_start(ScavengerConfig::default()));
+    let stats = wait_for("meshing", |stats| stats.mesh.meshed > 0 && stats.released > 0);
+    assert!(stats.mesh.meshed <= stats.mesh.pairs);
+
+    aura_scavenger_pause();
+    let paused = wait_for("the pass to end", |_| true).passes;
+    thread::sleep(Duration::from_millis(50));
+    assert!(aura_scavenger_stats().passes <= paused + 1);
+    aura_scavenger_resume();
+    wait_for("a pass after resuming", |stats| stats.passes > paused + 1);
+    aura_scavenger_stop();
+
+    // the bucket unlinks the blocks that were meshed away as it refills
+    let more = (0..64 * 64).map(|_| aura_alloc(size)).collect::<Vec<_>>();
+    for (i, obj) in kept {
+        assert!((0..size).all(|j| unsafe { *obj.add(j) } == i as u8));
+        aura_free(obj);
+    }
+    more.into_iter().for_each(aura_free);
+}
@@ -0,0 +1,47 @@
+#![cfg(target_os = "linux")]
+
+extern crate aura;
+
+mod common;
+
+use std::time::Duration;
+
+use aura::{aura_set_purge_delay, aura_set_segment_cache};
+use common::{empty, fill, resident_bytes, SEGMENT_SIZE};
+
+#[test]
+fn empty_segments_are_released() {
+    // only releasing segments gives memory back here
+    aura_set_purge_delay(Duration::from_secs(3600));
+
+    // cached segments stay resident
+    aura_set_segment_cache(usize::MAX);
+    let objs = fill(256);
+    let before = resident_bytes();
+    empty(objs);
+    let after = resident_bytes();
+    assert!(
+        before.saturating_sub(after) < SEGMENT_SIZE,
+        "cached segments shrank RSS from {} to {}",
+        before,
+        after
+    );
+
+    // the cache only shrinks as segments empty out again
+    aura_set_segment_cache(0);
+    let objs = fill(256);
+    let before = resident_bytes();
+    empty(objs);
+    let after = resident_bytes();
+    assert!(
+        before.saturating_sub(after) >= 2 * SEGMENT_SIZE,
+        "releasing segments only shrank RSS from {} to {}",
+        before,
+        after
+    );
+
+    // released address space is handed out again
+    let objs = fill(256);
+    assert!(objs.iter().all(|&obj| !obj.is_null()));
+    empty(objs);
+}
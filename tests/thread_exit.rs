@@ -0,0 +1,51 @@
+#![cfg(target_os = "linux")]
+
+extern crate aura;
+
+mod common;
+
+use std::thread;
+
+use aura::{aura_alloc, aura_free};
+use common::resident_bytes;
+
+const SIZES: [usize; 3] = [24, 400, 3000];
+
+/// Spawn short-lived threads one after the other. Each leaves a few objects
+/// behind for this thread to free once it's gone.
+fn churn(threads: usize) {
+    for _ in 0..threads {
+        let kept = thread::spawn(|| {
+            let mut kept = Vec::new();
+            for &size in &SIZES {
+                // a new block is formatted all the way through, so a block
+                // left behind per thread would show
+                let (obj, other) = (aura_alloc(size), aura_alloc(size));
+                unsafe { std::ptr::write_bytes(obj, 0x5a, size) };
+                kept.push(obj as usize);
+                aura_free(other);
+            }
+            kept
+        })
+        .join()
+        .unwrap();
+        for obj in kept {
+            aura_free(obj as *mut u8);
+        }
+    }
+}
+
+#[test]
+fn exited_threads_blocks_are_reused() {
+    churn(256);
+    let before = resident_bytes();
+    churn(4096);
+    let after = resident_bytes();
+    // without adoption, each thread would leave a block per size class behind
+    assert!(
+        after.saturating_sub(before) < 16 * 1024 * 1024,
+        "RSS grew from {} to {} over 4096 threads",
+        before,
+        after
+    );
+}
@@ -0,0 +1,47 @@
+#![cfg(feature = "trace")]
+
+extern crate aura;
+
+use aura::{aura_alloc, aura_free, aura_trace_dump, aura_trace_events, TraceKind, TRACE_EVENTS};
+
+const BLOCK_SIZE: usize = 64 * 1024;
+
+// frees are traced as they reach their block, so skip the quarantine
+fn free_now(obj: *mut u8) {
+    #[cfg(feature = "debug-uaf")]
+    aura::aura_set_quarantine_bytes(0);
+    aura_free(obj)
+}
+
+#[test]
+fn allocs_and_frees_are_traced() {
+    let obj = aura_alloc(48);
+    free_now(obj);
+
+    let mut events = Vec::new();
+    aura_trace_events(|event| events.push(*event));
+    let (alloc, free) = match &events[..] {
+        [.., alloc, free] => (alloc, free),
+        _ => panic!("expected an alloc and a free, got {:?}", events),
+    };
+    // block headers sit in the segment's first block, ahead of the bodies
+    let segment = obj as usize & !(64 * BLOCK_SIZE - 1);
+    assert_eq!((alloc.kind, free.kind), (TraceKind::Alloc, TraceKind::Free));
+    assert_eq!(alloc.block, free.block);
+    assert!(alloc.block >= segment && alloc.block < segment + BLOCK_SIZE);
+    assert_eq!(free.prev_count, alloc.prev_count + 1);
+    aura_trace_dump();
+}
+
+#[test]
+fn only_recent_events_are_kept() {
+    let objs = (0..2 * TRACE_EVENTS).map(|_| aura_alloc(16)).collect::<Vec<_>>();
+    objs.into_iter().for_each(free_now);
+
+    let mut events = 0;
+    aura_trace_events(|event| {
+        assert_eq!(event.kind, TraceKind::Free);
+        events += 1;
+    });
+    assert_eq!(events, TRACE_EVENTS);
+}